@@ -1,22 +1,43 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::io::ErrorKind;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
 use std::sync::RwLock;
 
-use prost::bytes::Bytes;
+use bytes::Bytes;
 use chrono::prelude::*;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
-use lz4_compress::{compress, decompress};
 use prometheus::{Encoder, TextEncoder};
 use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::config::{Config, CorruptRead};
 use crate::metrics::{HTTP_BYTES_IN, HTTP_BYTES_OUT, HTTP_COUNTER, HTTP_REQ_HISTOGRAM};
-use crate::stora::disk::{mark_block_as_deleted, read_block, DISK};
-use crate::stora::meta::HashFun::{Other, Hgw128, Hgw256, Md5, Sha128, Sha256};
-use crate::stora::meta::{BlockMeta, Compression, HashFun};
+use crate::stora::disk::{
+    append_block, erase_block, mark_block_as_deleted, mark_blocks_as_deleted,
+    mark_object_as_deleted, stream_block, undelete_block, write_block,
+};
+use crate::stora::meta::HashFun::{Hgw128, Hgw256, Md5, Other, Sha128, Sha256};
+use crate::stora::meta::{
+    BlockFilter, BlockMeta, Compression, CorruptBlock, HashFun, Precondition,
+};
 use crate::stora::status::Status;
+use crate::stora::validator;
+
+/// Request headers with this prefix are kept as user metadata of the block.
+const USER_META_PREFIX: &str = "x-v-meta-";
+
+/// Page size of GET /blocks.
+const LIST_LIMIT_DEFAULT: usize = 1000;
+const LIST_LIMIT_MAX: usize = 10000;
+/// Block ids accepted by the batch calls.
+const BATCH_SIZE_MAX: usize = 1000;
+/// Payload bytes returned by POST /batch/get, as in the rpc.
+const BATCH_PAYLOAD_MAX: usize = 4 * 1024 * 1024;
 
 lazy_static! {
     pub static ref CONFIG: RwLock<Option<Config>> = RwLock::new(None);
@@ -51,19 +72,21 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
         .with_label_values(&[req.method().as_str()])
         .start_timer();
 
-    let hash_fun = |req: &Request<Body>| -> HashFun {
+    let hash_fun = |req: &Request<Body>| -> Option<HashFun> {
         let hash_fun_header_name = "v-hash-fun";
         if req.headers().contains_key(hash_fun_header_name) {
-            match req.headers().get(hash_fun_header_name).unwrap().as_bytes() {
-                b"1" => Md5,
-                b"2" => Sha128,
-                b"3" => Sha256,
-                b"4" => Hgw128,
-                b"5" => Hgw256,
-                _ => Other,
-            }
+            Some(
+                match req.headers().get(hash_fun_header_name).unwrap().as_bytes() {
+                    b"1" => Md5,
+                    b"2" => Sha128,
+                    b"3" => Sha256,
+                    b"4" => Hgw128,
+                    b"5" => Hgw256,
+                    _ => Other,
+                },
+            )
         } else {
-            Hgw128
+            None
         }
     };
 
@@ -121,24 +144,58 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
         }
     };
 
-    let payload_size = |req: &Request<Body>| -> u64 {
-        let size_header_name = "content-length";
-        if req.headers().contains_key(size_header_name) {
+    let content_type = |req: &Request<Body>| -> String {
+        let content_type_header_name = "content-type";
+        if req.headers().contains_key(content_type_header_name) {
             String::from_utf8(
                 req.headers()
-                    .get(size_header_name)
+                    .get(content_type_header_name)
                     .unwrap()
                     .as_bytes()
                     .to_vec(),
             )
-            .unwrap()
-            .parse::<u64>()
-            .unwrap()
+            .unwrap_or_default()
         } else {
-            0
+            "".to_string()
+        }
+    };
+
+    let user_meta = |req: &Request<Body>| -> HashMap<String, String> {
+        req.headers()
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with(USER_META_PREFIX))
+            .filter_map(|(name, value)| match value.to_str() {
+                Ok(value) => Some((
+                    name.as_str()[USER_META_PREFIX.len()..].to_string(),
+                    value.to_string(),
+                )),
+                Err(_) => None,
+            })
+            .collect()
+    };
+
+    // v-ttl counts seconds from the creation and wins over the v-expires-at unix time
+    let expires_at = |req: &Request<Body>, created: u64| -> u64 {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+        };
+        match header("v-ttl") {
+            Some(ttl) if ttl > 0 => created + ttl,
+            _ => header("v-expires-at").unwrap_or(0),
         }
     };
 
+    // None for a body without Content-Length, e.g. a chunked one
+    let payload_size = |req: &Request<Body>| -> Option<u64> {
+        req.headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+    };
+
     let etag = |req: &Request<Body>| -> String {
         let etag_header_name = "if-none-match";
         if req.headers().contains_key(etag_header_name) {
@@ -172,6 +229,56 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
         }
     };
 
+    let if_match = |req: &Request<Body>| -> Option<String> {
+        let if_match_header_name = "if-match";
+        if req.headers().contains_key(if_match_header_name) {
+            String::from_utf8(
+                req.headers()
+                    .get(if_match_header_name)
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec(),
+            )
+            .ok()
+            .map(|tag| tag.replace("\"", ""))
+        } else {
+            None
+        }
+    };
+
+    let range = |req: &Request<Body>| -> Option<String> {
+        let range_header_name = "range";
+        if req.headers().contains_key(range_header_name) {
+            String::from_utf8(
+                req.headers()
+                    .get(range_header_name)
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec(),
+            )
+            .ok()
+        } else {
+            None
+        }
+    };
+
+    let if_range = |req: &Request<Body>| -> Option<String> {
+        let if_range_header_name = "if-range";
+        if req.headers().contains_key(if_range_header_name) {
+            String::from_utf8(
+                req.headers()
+                    .get(if_range_header_name)
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec(),
+            )
+            .ok()
+            .map(|tag| tag.replace("\"", ""))
+        } else {
+            None
+        }
+    };
+
     match (req.method(), (cmd, tokens_len), path.as_str()) {
         // -----------------------------------------------------------------------------------------
         (&Method::GET, _, "/") | (&Method::GET, _, "/index.html") | (&Method::GET, _, "") => {
@@ -214,14 +321,251 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
             res
         }
         // -----------------------------------------------------------------------------------------
-        (&Method::HEAD, ("block", 2), _) => {
+        (&Method::GET, ("blocks", 1), _) => {
+            let params = query_params(&req);
+            let param = |name: &str| params.get(name).filter(|v| !v.is_empty()).cloned();
+            let number = |name: &str| param(name).and_then(|v| v.parse::<u64>().ok());
+            let filter = BlockFilter {
+                volume_id: param("volume_id"),
+                bucket_ids: param("bucket_id")
+                    .map(|ids| {
+                        ids.split(',')
+                            .filter_map(|id| id.parse::<u32>().ok())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                content_type: param("content_type"),
+                created_from: number("created_from"),
+                created_to: number("created_to"),
+                size_min: number("size_min"),
+                size_max: number("size_max"),
+            };
+            let limit = match number("limit") {
+                Some(limit) if limit > 0 => std::cmp::min(limit as usize, LIST_LIMIT_MAX),
+                _ => LIST_LIMIT_DEFAULT,
+            };
+            let cursor = param("cursor").unwrap_or_default();
+            let page = BlockMeta::list(&filter, &cursor, limit)
+                .map_err(|e| e.to_string())
+                .and_then(|(blocks, next)| {
+                    serde_json::to_string(&serde_json::json!({
+                        "blocks": blocks,
+                        "cursor": next,
+                    }))
+                    .map_err(|e| e.to_string())
+                });
+            match page {
+                Ok(page) => {
+                    HTTP_BYTES_OUT.inc_by(page.len() as f64);
+                    let mut res = Response::new(Body::from(page));
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        http::header::HeaderValue::from_str("application/json").unwrap(),
+                    );
+                    timer.observe_duration();
+                    Ok(res)
+                }
+                Err(e) => {
+                    error!("list blocks: {}", e);
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    timer.observe_duration();
+                    Ok(res)
+                }
+            }
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::GET, ("corrupt", 1), _) => {
+            let params = query_params(&req);
+            let limit = match params.get("limit").and_then(|v| v.parse::<usize>().ok()) {
+                Some(limit) if limit > 0 => std::cmp::min(limit, LIST_LIMIT_MAX),
+                _ => LIST_LIMIT_DEFAULT,
+            };
+            let cursor = params.get("cursor").cloned().unwrap_or_default();
+            let page = CorruptBlock::list(&cursor, limit)
+                .map_err(|e| e.to_string())
+                .and_then(|(entries, next)| {
+                    serde_json::to_string(&serde_json::json!({
+                        "blocks": entries,
+                        "cursor": next,
+                    }))
+                    .map_err(|e| e.to_string())
+                });
+            match page {
+                Ok(page) => {
+                    HTTP_BYTES_OUT.inc_by(page.len() as f64);
+                    let mut res = Response::new(Body::from(page));
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        http::header::HeaderValue::from_str("application/json").unwrap(),
+                    );
+                    timer.observe_duration();
+                    Ok(res)
+                }
+                Err(e) => {
+                    error!("list corrupt blocks: {}", e);
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    timer.observe_duration();
+                    Ok(res)
+                }
+            }
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::POST, ("corrupt", 2), _) => {
+            // checks the block right away instead of waiting for the validator pass
+            let block_id = tokens[1].to_string();
+            let content_hash = CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_check_content_hash;
+            let res = match validator::check_now(&block_id, content_hash) {
+                Ok(Some(entry)) => {
+                    let body = serde_json::to_string(&entry).unwrap_or_default();
+                    HTTP_BYTES_OUT.inc_by(body.len() as f64);
+                    let mut res = Response::new(Body::from(body));
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        http::header::HeaderValue::from_str("application/json").unwrap(),
+                    );
+                    res
+                }
+                Ok(None) => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::NO_CONTENT;
+                    res
+                }
+                Err(_) => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                    res
+                }
+            };
+            timer.observe_duration();
+            Ok(res)
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::DELETE, ("corrupt", 2), _) => {
             let block_id = tokens[1].to_string();
-            let code = match BlockMeta::exists(block_id) {
-                Ok(true) => StatusCode::FOUND,
-                _ => StatusCode::NOT_FOUND,
+            let mut res = Response::default();
+            *res.status_mut() = match CorruptBlock::acknowledge(&block_id) {
+                Ok(true) => StatusCode::NO_CONTENT,
+                Ok(false) => StatusCode::NOT_FOUND,
+                Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            timer.observe_duration();
+            Ok(res)
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::POST, ("batch", 2), _) => {
+            let op = tokens[1].to_string();
+            let (block_ids, omit_payload) = match batch_request(req).await {
+                Some((block_ids, omit_payload)) if block_ids.len() <= BATCH_SIZE_MAX => {
+                    (block_ids, omit_payload)
+                }
+                _ => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::BAD_REQUEST;
+                    timer.observe_duration();
+                    return Ok(res);
+                }
             };
+            let reply = match op.as_str() {
+                "exists" => BlockMeta::get_many(&block_ids).map_err(|_| ()).map(|metas| {
+                    let items = block_ids
+                        .iter()
+                        .zip(metas.iter())
+                        .map(|(id, meta)| serde_json::json!({"block_id": id, "found": meta.is_some()}))
+                        .collect::<Vec<_>>();
+                    serde_json::json!({ "items": items })
+                }),
+                "get" => match BlockMeta::get_many(&block_ids) {
+                    Ok(metas) => Ok(batch_get_items(&block_ids, metas, omit_payload).await),
+                    Err(_) => Err(()),
+                },
+                "delete" => mark_blocks_as_deleted(&block_ids)
+                    .map(|deleted| serde_json::json!({ "block_ids": deleted })),
+                _ => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                    timer.observe_duration();
+                    return Ok(res);
+                }
+            };
+            match reply {
+                Ok(reply) => {
+                    let body = reply.to_string();
+                    HTTP_BYTES_OUT.inc_by(body.len() as f64);
+                    let mut res = Response::new(Body::from(body));
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        http::header::HeaderValue::from_str("application/json").unwrap(),
+                    );
+                    timer.observe_duration();
+                    Ok(res)
+                }
+                Err(_) => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    timer.observe_duration();
+                    Ok(res)
+                }
+            }
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::GET, ("object", 3), _) if tokens[2] == "blocks" => {
+            let object_id = path_param(&req, 1);
+            let blocks = BlockMeta::fetch_by_object(&object_id)
+                .map_err(|e| e.to_string())
+                .and_then(|blocks| serde_json::to_string(&blocks).map_err(|e| e.to_string()));
+            match blocks {
+                Ok(blocks) => {
+                    HTTP_BYTES_OUT.inc_by(blocks.len() as f64);
+                    let mut res = Response::new(Body::from(blocks));
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        http::header::HeaderValue::from_str("application/json").unwrap(),
+                    );
+                    timer.observe_duration();
+                    Ok(res)
+                }
+                Err(e) => {
+                    error!("object blocks: {}", e);
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    timer.observe_duration();
+                    Ok(res)
+                }
+            }
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::HEAD, ("block", 2), _) => {
+            let block_id = tokens[1].to_string();
             let mut res = Response::default();
-            *res.status_mut() = code;
+            match BlockMeta::get(block_id) {
+                Ok(Some(meta)) => match corrupt_warning(&meta) {
+                    Err(warning) => {
+                        *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        res.headers_mut().insert(http::header::WARNING, warning);
+                    }
+                    Ok(warning) => {
+                        let mut headers = meta_headers(&meta);
+                        headers.insert(
+                            http::header::CONTENT_LENGTH,
+                            http::header::HeaderValue::from(meta.orig_size),
+                        );
+                        if let Some(warning) = warning {
+                            headers.insert(http::header::WARNING, warning);
+                        }
+                        *res.status_mut() = StatusCode::OK;
+                        *res.headers_mut() = headers;
+                    }
+                },
+                _ => *res.status_mut() = StatusCode::NOT_FOUND,
+            }
             timer.observe_duration();
             Ok(res)
         }
@@ -230,6 +574,16 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
             let block_id = tokens[1].to_string();
             match BlockMeta::get(block_id) {
                 Ok(Some(meta)) => {
+                    let warning = match corrupt_warning(&meta) {
+                        Ok(warning) => warning,
+                        Err(warning) => {
+                            let mut res = Response::default();
+                            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                            res.headers_mut().insert(http::header::WARNING, warning);
+                            timer.observe_duration();
+                            return Ok(res);
+                        }
+                    };
                     let etag = etag(&req);
                     if !etag.eq("") && etag.eq(&meta.crc) {
                         let mut res = Response::default();
@@ -237,15 +591,40 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
                         timer.observe_duration();
                         return Ok(res);
                     }
-                    let lz4_transfer = accept_encoding(&req).find("lz4").is_some();
-                    let body = match read_block(&meta.path) {
-                        Ok(content) => {
-                            if meta.compressed && !lz4_transfer {
-                                decompress(content.as_slice()).unwrap()
-                            } else {
-                                content
-                            }
+                    let last_modified = last_modified(&meta);
+                    let range = match if_range(&req) {
+                        Some(tag) if !tag.eq(&meta.crc) && !tag.eq(&last_modified) => None,
+                        _ => range(&req),
+                    };
+                    // a range applies to the original bytes, ranged reads of compressed blocks are decompressed
+                    let lz4_transfer =
+                        accept_encoding(&req).find("lz4").is_some() && range.is_none();
+                    let decompressed = meta.compressed && !lz4_transfer;
+                    let size = if decompressed || !meta.compressed {
+                        meta.orig_size
+                    } else {
+                        meta.size
+                    };
+                    let (offset, body_len) = match range.map(|r| byte_range(&r, size)) {
+                        None => (0, size),
+                        Some(Some(r)) => r,
+                        Some(None) => {
+                            let mut res = Response::default();
+                            *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                            res.headers_mut().insert(
+                                http::header::CONTENT_RANGE,
+                                http::header::HeaderValue::from_str(
+                                    format!("bytes */{}", size).as_str(),
+                                )
+                                .unwrap(),
+                            );
+                            timer.observe_duration();
+                            return Ok(res);
                         }
+                    };
+                    let partial = body_len < size;
+                    let body = match stream_block(&meta, offset, body_len, decompressed) {
+                        Ok(parts) => Body::wrap_stream(parts),
                         Err(e) => {
                             error!("can't read block: {}", e);
                             let mut res = Response::default();
@@ -255,43 +634,41 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
                         }
                     };
 
-                    let mut headers = HeaderMap::new();
+                    let mut headers = meta_headers(&meta);
                     headers.insert(
                         http::header::CONTENT_LENGTH,
-                        http::header::HeaderValue::from(body.len()),
-                    );
-                    headers.insert(
-                        http::header::ETAG,
-                        http::header::HeaderValue::from_str(format!("\"{}\"", meta.crc).as_str())
-                            .unwrap(),
-                    );
-                    headers.insert(
-                        http::header::SERVER,
-                        http::header::HeaderValue::from_str("vbs").unwrap(),
-                    );
-                    headers.insert(
-                        http::header::LAST_MODIFIED,
-                        http::header::HeaderValue::from_str(
-                            Utc.timestamp(meta.created as i64, 0)
-                                .format("%a, %d %b %Y %T GMT")
-                                .to_string()
-                                .as_str(),
-                        )
-                        .unwrap(),
+                        http::header::HeaderValue::from(body_len),
                     );
+                    if let Some(warning) = warning {
+                        headers.insert(http::header::WARNING, warning);
+                    }
                     if meta.compressed && lz4_transfer {
                         headers.insert(
                             http::header::CONTENT_ENCODING,
                             http::header::HeaderValue::from_str("lz4").unwrap(),
                         );
                     }
+                    if partial {
+                        headers.insert(
+                            http::header::CONTENT_RANGE,
+                            http::header::HeaderValue::from_str(
+                                format!("bytes {}-{}/{}", offset, offset + body_len - 1, size)
+                                    .as_str(),
+                            )
+                            .unwrap(),
+                        );
+                    }
 
-                    HTTP_BYTES_OUT.inc_by(body.len() as f64);
+                    HTTP_BYTES_OUT.inc_by(body_len as f64);
 
                     let mut res = Response::default();
-                    *res.status_mut() = StatusCode::OK;
+                    *res.status_mut() = if partial {
+                        StatusCode::PARTIAL_CONTENT
+                    } else {
+                        StatusCode::OK
+                    };
                     *res.headers_mut() = headers;
-                    *res.body_mut() = Body::from(body);
+                    *res.body_mut() = body;
 
                     timer.observe_duration();
                     Ok(res)
@@ -318,25 +695,33 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
                 return Ok(res);
             };
 
-            if payload_size(&req) > CONFIG.read().unwrap().clone().unwrap().storage.block_size_limit_bytes
-            {
-                let mut res = Response::default();
-                *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
-
-                timer.observe_duration();
-                return Ok(res);
-            }
-
-            let payload= hyper::body::to_bytes(req.into_body()).await.unwrap().to_vec();
+            let precondition = match if_match(&req) {
+                Some(crc) => Precondition::Crc(crc),
+                None => Precondition::Any,
+            };
+            let hash = hash(&req);
+            let limit = CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_size_limit_bytes;
+            let declared = payload_size(&req);
+            let (size, parts) = match sized_body(req, declared, limit).await {
+                Ok(body) => body,
+                Err(code) => {
+                    let mut res = Response::default();
+                    *res.status_mut() = code;
 
-            let code = match BlockMeta::append(block_id, payload) {
-                Ok(Some(_meta)) => {
-                    StatusCode::NO_CONTENT
-                }
-                _ => {
-                    StatusCode::NOT_FOUND
+                    timer.observe_duration();
+                    return Ok(res);
                 }
             };
+            let code = match append_block(&block_id, size, parts, hash, precondition).await {
+                Ok(_meta) => StatusCode::NO_CONTENT,
+                Err(e) => write_error_code(e),
+            };
             let mut res = Response::default();
             *res.status_mut() = code;
 
@@ -353,8 +738,14 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
                 format!("{}", Uuid::new_v4().to_simple())
             };
 
-            if payload_size(&req) > CONFIG.read().unwrap().clone().unwrap().storage.block_size_limit_bytes
-            {
+            let limit = CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_size_limit_bytes;
+            if payload_size(&req).unwrap_or(0) > limit {
                 let mut res = Response::default();
                 *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
 
@@ -362,79 +753,50 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
                 return Ok(res);
             }
 
-            match req.method() {
-                &Method::PUT => {
-                    // check that block with this id is not exists
-                    if let Ok(true) = BlockMeta::exists(block_id.clone()) {
-                        let mut res = Response::default();
-                        *res.status_mut() = StatusCode::FOUND;
+            let precondition = match (if_match(&req), etag(&req).as_str()) {
+                (Some(crc), _) => Precondition::Crc(crc),
+                (None, "*") => Precondition::Absent,
+                _ => match req.method() {
+                    &Method::PUT => {
+                        // check that block with this id is not exists
+                        if let Ok(true) = BlockMeta::exists(block_id.clone()) {
+                            let mut res = Response::default();
+                            *res.status_mut() = StatusCode::FOUND;
 
-                        timer.observe_duration();
-                        return Ok(res);
+                            timer.observe_duration();
+                            return Ok(res);
+                        }
+                        Precondition::Absent
                     }
-                }
-                _ => (),
-            }
+                    _ => Precondition::Any,
+                },
+            };
 
             let mut b = BlockMeta::new();
             b.id = block_id.to_owned();
             b.object_id = object_id(&req);
-            b.hash_fun = hash_fun(&req);
+            b.content_type = content_type(&req);
+            b.user_meta = user_meta(&req);
+            b.expires_at = expires_at(&req, b.created);
             b.hash = hash(&req);
-            b.size = payload_size(&req);
+            b.hash_fun = HashFun::of_write(hash_fun(&req), &b.hash);
             b.compressed = compression(&req) == Compression::LZ4;
-            b.orig_size = b.size;
             b.last_check_ts = Utc::now().timestamp() as u64;
-            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
-
-            HTTP_BYTES_IN.inc_by(body.len() as f64);
-
-            if body.len() != b.orig_size as usize {
-                let mut res = Response::default();
-                *res.status_mut() = StatusCode::LENGTH_REQUIRED;
-
-                timer.observe_duration();
-                return Ok(res);
-            }
-
-            let slot = { DISK.write().unwrap().get_write_slot() };
-            match slot {
-                Ok(slot) => {
-                    let body = if b.compressed {
-                        let compressed_body = compress(&body.to_vec());
-                        if compressed_body.len() < body.len() {
-                            b.size = compressed_body.len() as u64;
-                            b.compressed = true;
-                            Bytes::from(compressed_body)
-                        } else {
-                            b.compressed = false;
-                            body
-                        }
-                    } else {
-                        body
-                    };
-
-                    match slot.clone().store(body.to_vec()) {
-                        Ok(saved_file) => {
-                            b.volume_id = slot.volume_id.to_owned();
-                            b.bucket_id = slot.bucket_id.to_owned();
-                            b.path = saved_file;
-                            b.crc = BlockMeta::crc(body.to_vec());
-                            if let Err(_) = slot.commit(b) {
-                                error!("can't commit slot");
-                            }
-                        }
-                        Err(e) => {
-                            slot.release(0);
-                            error!("can't write payload {}", e);
-                            let mut res = Response::default();
-                            *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            let declared = payload_size(&req);
+            let (size, parts) = match sized_body(req, declared, limit).await {
+                Ok(body) => body,
+                Err(code) => {
+                    let mut res = Response::default();
+                    *res.status_mut() = code;
 
-                            timer.observe_duration();
-                            return Ok(res);
-                        }
-                    }
+                    timer.observe_duration();
+                    return Ok(res);
+                }
+            };
+            b.orig_size = size;
 
+            match write_block(b, parts, precondition).await {
+                Ok(_meta) => {
                     let mut res = Response::default();
                     if argc > 1 {
                         *res.status_mut() = StatusCode::NO_CONTENT;
@@ -446,10 +808,10 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
                     timer.observe_duration();
                     Ok(res)
                 }
-                Err(_) => {
-                    error!("disk get slot");
+                Err(e) => {
+                    error!("can't write payload {}", e);
                     let mut res = Response::default();
-                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    *res.status_mut() = write_error_code(e);
 
                     timer.observe_duration();
                     Ok(res)
@@ -457,8 +819,68 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
             }
         }
         // -----------------------------------------------------------------------------------------
+        (&Method::PATCH, ("block", 2), _) => {
+            let block_id = tokens[1].to_string();
+            let mut meta = match BlockMeta::get(block_id) {
+                Ok(Some(meta)) => meta,
+                _ => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                    timer.observe_duration();
+                    return Ok(res);
+                }
+            };
+            if let Some(tag) = if_match(&req) {
+                if !tag.eq(&meta.crc) {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::PRECONDITION_FAILED;
+                    timer.observe_duration();
+                    return Ok(res);
+                }
+            }
+            let object_id = object_id(&req);
+            if !object_id.eq("") {
+                meta.object_id = object_id;
+            }
+            let content_type = content_type(&req);
+            if !content_type.eq("") {
+                meta.content_type = content_type;
+            }
+            meta.merge_user_meta(user_meta(&req), false);
+            // the payload location is compared on update, so the crc can't change in between
+            let code = match meta.update() {
+                Ok(_) => StatusCode::NO_CONTENT,
+                Err(e) => match e.kind() {
+                    ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    ErrorKind::Interrupted => StatusCode::CONFLICT,
+                    _ => StatusCode::SERVICE_UNAVAILABLE,
+                },
+            };
+            let mut res = Response::default();
+            *res.status_mut() = code;
+            timer.observe_duration();
+            Ok(res)
+        }
+        // -----------------------------------------------------------------------------------------
         (&Method::DELETE, ("block", 2), _) => {
             let block_id = tokens[1].to_string();
+            // ?direct=true erases the block before the reply instead of moving it to the trash
+            let direct = query_params(&req)
+                .get("direct")
+                .map_or(false, |v| v.eq("true") || v.eq("1"));
+            if direct {
+                let mut res = Response::default();
+                *res.status_mut() = match erase_block(&block_id) {
+                    Ok(true) => StatusCode::NO_CONTENT,
+                    Ok(false) => StatusCode::NOT_FOUND,
+                    Err(e) => {
+                        error!("can't erase block: {}", e);
+                        StatusCode::SERVICE_UNAVAILABLE
+                    }
+                };
+                timer.observe_duration();
+                return Ok(res);
+            }
             match BlockMeta::get(block_id) {
                 Ok(Some(meta)) => {
                     if let Err(_) = mark_block_as_deleted(meta) {
@@ -478,6 +900,55 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
             }
         }
         // -----------------------------------------------------------------------------------------
+        (method, ("block", 2), _) if method.as_str() == "UNDELETE" => {
+            let block_id = tokens[1].to_string();
+            let mut res = Response::default();
+            *res.status_mut() = match undelete_block(&block_id) {
+                Ok(_) => StatusCode::NO_CONTENT,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+                Err(e) => {
+                    error!("can't undelete block: {}", e);
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+            };
+            timer.observe_duration();
+            Ok(res)
+        }
+        // -----------------------------------------------------------------------------------------
+        (&Method::DELETE, ("object", 2), _) => {
+            let object_id = path_param(&req, 1);
+            match mark_object_as_deleted(&object_id) {
+                Ok(block_ids) if block_ids.is_empty() => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                    timer.observe_duration();
+                    Ok(res)
+                }
+                Ok(block_ids) => {
+                    let body = serde_json::json!({
+                        "object_id": object_id,
+                        "block_ids": block_ids,
+                    })
+                    .to_string();
+                    HTTP_BYTES_OUT.inc_by(body.len() as f64);
+                    let mut res = Response::new(Body::from(body));
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        http::header::HeaderValue::from_str("application/json").unwrap(),
+                    );
+                    timer.observe_duration();
+                    Ok(res)
+                }
+                Err(_) => {
+                    let mut res = Response::default();
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    timer.observe_duration();
+                    Ok(res)
+                }
+            }
+        }
+        // -----------------------------------------------------------------------------------------
         _ => {
             let mut not_found = Response::default();
             *not_found.status_mut() = StatusCode::NOT_FOUND;
@@ -486,6 +957,306 @@ async fn block_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     }
 }
 
+fn last_modified(meta: &BlockMeta) -> String {
+    Utc.timestamp(meta.created as i64, 0)
+        .format("%a, %d %b %Y %T GMT")
+        .to_string()
+}
+
+/// Warning header for a block quarantined by the validator,
+/// `Err` when reads of quarantined blocks are refused.
+fn corrupt_warning(
+    meta: &BlockMeta,
+) -> Result<Option<http::header::HeaderValue>, http::header::HeaderValue> {
+    let entry = match CorruptBlock::quarantined(meta) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let warning = http::header::HeaderValue::from_str(
+        format!(
+            "199 vbs \"block is quarantined: {}\"",
+            entry.reason.as_str()
+        )
+        .as_str(),
+    )
+    .unwrap();
+    match CONFIG.read().unwrap().clone().unwrap().storage.corrupt_read {
+        CorruptRead::Warn => Ok(Some(warning)),
+        CorruptRead::Refuse => Err(warning),
+    }
+}
+
+/// Block metadata as response headers, mirrors the rpc `Meta` message.
+/// Values which can't be sent as a header are skipped.
+fn meta_headers(meta: &BlockMeta) -> HeaderMap {
+    let hash_fun = match meta.hash_fun {
+        Other => 0,
+        Md5 => 1,
+        Sha128 => 2,
+        Sha256 => 3,
+        Hgw128 => 4,
+        Hgw256 => 5,
+    };
+    let mut headers = HeaderMap::new();
+    let mut insert = |name: &'static str, value: String| {
+        if let Ok(value) = http::header::HeaderValue::from_str(value.as_str()) {
+            headers.insert(name, value);
+        }
+    };
+    if !meta.content_type.is_empty() {
+        insert("content-type", meta.content_type.to_owned());
+    }
+    insert("etag", format!("\"{}\"", meta.crc));
+    insert("server", "vbs".to_string());
+    insert("last-modified", last_modified(meta));
+    insert("accept-ranges", "bytes".to_string());
+    insert("v-object-id", meta.object_id.to_owned());
+    insert("v-hash", meta.hash.to_owned());
+    insert("v-hash-fun", hash_fun.to_string());
+    insert("v-size", meta.orig_size.to_string());
+    insert("v-stored-size", meta.size.to_string());
+    if meta.compressed {
+        insert("v-compress", "lz4".to_string());
+    }
+    if meta.expires_at != 0 {
+        insert("v-expires-at", meta.expires_at.to_string());
+    }
+    for (key, value) in meta.user_meta.iter() {
+        if let Ok(name) =
+            http::header::HeaderName::from_bytes(format!("{}{}", USER_META_PREFIX, key).as_bytes())
+        {
+            if let Ok(value) = http::header::HeaderValue::from_str(value.as_str()) {
+                headers.insert(name, value);
+            }
+        }
+    }
+    headers
+}
+
+/// Resolves a single `bytes=` range against the size into offset and length.
+/// Returns None for an unsatisfiable range, multiple ranges are served as a whole.
+fn byte_range(range: &String, size: u64) -> Option<(u64, u64)> {
+    let spec = match range.trim().starts_with("bytes=") {
+        true => range.trim()[6..].trim(),
+        false => return Some((0, size)),
+    };
+    if spec.contains(',') {
+        return Some((0, size));
+    }
+    let mut bounds = spec.splitn(2, '-');
+    let (first, last) = (bounds.next().unwrap_or(""), bounds.next().unwrap_or(""));
+    let (start, end) = match (first.trim().parse::<u64>(), last.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, std::cmp::min(end, size.saturating_sub(1))),
+        (Ok(start), Err(_)) if last.trim().is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.trim().is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Some((0, size)),
+    };
+    if start >= size {
+        return None;
+    }
+    Some((start, end - start + 1))
+}
+
+/// Body of a batch request: {"block_ids": ["id1", "id2"], "omit_payload": true},
+/// `omit_payload` applies to batch get only and defaults to false as in the rpc.
+async fn batch_request(req: Request<Body>) -> Option<(Vec<String>, bool)> {
+    let body = hyper::body::to_bytes(req.into_body()).await.ok()?;
+    HTTP_BYTES_IN.inc_by(body.len() as f64);
+    let body: serde_json::Value = serde_json::from_slice(&body).ok()?;
+    let block_ids = body
+        .get("block_ids")?
+        .as_array()?
+        .iter()
+        .map(|id| id.as_str().map(|id| id.to_string()))
+        .collect::<Option<Vec<_>>>()?;
+    let omit_payload = body
+        .get("omit_payload")
+        .and_then(|omit| omit.as_bool())
+        .unwrap_or(false);
+    Some((block_ids, omit_payload))
+}
+
+/// Items of a batch get reply. Payloads are decompressed and sent in base64,
+/// at most BATCH_PAYLOAD_MAX bytes of them per reply.
+async fn batch_get_items(
+    block_ids: &Vec<String>,
+    metas: Vec<Option<BlockMeta>>,
+    omit_payload: bool,
+) -> serde_json::Value {
+    let mut items = Vec::with_capacity(metas.len());
+    let mut payload_bytes: usize = 0;
+    for (id, meta) in block_ids.iter().zip(metas.into_iter()) {
+        let meta = match meta {
+            Some(meta) => meta,
+            None => {
+                items.push(serde_json::json!({"block_id": id, "error": "not found"}));
+                continue;
+            }
+        };
+        let mut item = serde_json::json!({"block_id": id, "meta": meta});
+        if let Err(warning) = corrupt_warning(&meta) {
+            item["error"] = serde_json::json!(warning.to_str().unwrap_or_default());
+            items.push(item);
+            continue;
+        }
+        if !omit_payload {
+            if payload_bytes + meta.orig_size as usize > BATCH_PAYLOAD_MAX {
+                item["error"] = serde_json::json!("batch payload limit exceeded");
+                items.push(item);
+                continue;
+            }
+            let body = match stream_block(&meta, 0, meta.orig_size, meta.compressed) {
+                Ok(parts) => parts.try_concat().await,
+                Err(e) => Err(e),
+            };
+            match body {
+                Ok(body) => {
+                    payload_bytes += body.len();
+                    item["payload"] = serde_json::json!(base64_encode(&body));
+                }
+                Err(e) => {
+                    error!("can't read block: {}", e);
+                    item["error"] = serde_json::json!("disk issue on this machine");
+                }
+            }
+        }
+        items.push(item);
+    }
+    serde_json::json!({ "items": items })
+}
+
+// json has no binary strings
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut res = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+/// Query string parameters, values are percent-decoded.
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            (
+                percent_decode(kv.next().unwrap_or(""), true),
+                percent_decode(kv.next().unwrap_or(""), true),
+            )
+        })
+        .collect()
+}
+
+/// Path segment after the command as sent by the client: the routing tokens
+/// are lowercased, ids are case-sensitive.
+fn path_param(req: &Request<Body>, idx: usize) -> String {
+    req.uri()
+        .path()
+        .trim_end_matches('/')
+        .split('/')
+        .nth(idx + 1)
+        .map(|segment| percent_decode(segment, false))
+        .unwrap_or_default()
+}
+
+// `+` stands for a space in query strings only
+fn percent_decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut res: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => res.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        res.push(b);
+                        i += 2;
+                    }
+                    Err(_) => res.push(b'%'),
+                }
+            }
+            b => res.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).to_string()
+}
+
+type BodyParts = Pin<Box<dyn Stream<Item = Result<Bytes, String>> + Send>>;
+
+/// Size and parts of the request body. The record size must be known before the
+/// payload is written, so a body without Content-Length is read up to its end
+/// first, at most `limit` bytes of it.
+async fn sized_body(
+    req: Request<Body>,
+    size: Option<u64>,
+    limit: u64,
+) -> Result<(u64, BodyParts), StatusCode> {
+    match size {
+        Some(size) if size > limit => Err(StatusCode::PAYLOAD_TOO_LARGE),
+        Some(size) => Ok((size, Box::pin(body_parts(req)))),
+        None => {
+            let mut parts = body_parts(req);
+            let mut payload: Vec<u8> = vec![];
+            while let Some(part) = parts.next().await {
+                let part = part.map_err(|_| StatusCode::BAD_REQUEST)?;
+                if (payload.len() + part.len()) as u64 > limit {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                payload.extend_from_slice(&part);
+            }
+            let size = payload.len() as u64;
+            Ok((
+                size,
+                Box::pin(stream::once(future::ready(Ok(Bytes::from(payload))))),
+            ))
+        }
+    }
+}
+
+// the request body goes to the write slot part by part
+fn body_parts(req: Request<Body>) -> impl Stream<Item = Result<Bytes, String>> {
+    req.into_body().map(|part| match part {
+        Ok(part) => {
+            HTTP_BYTES_IN.inc_by(part.len() as f64);
+            Ok(part)
+        }
+        Err(e) => Err(e.to_string()),
+    })
+}
+
+fn write_error_code(e: std::io::Error) -> StatusCode {
+    match e.kind() {
+        ErrorKind::InvalidInput => StatusCode::LENGTH_REQUIRED,
+        ErrorKind::ConnectionAborted => StatusCode::BAD_REQUEST,
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        // the block was changed by a concurrent write
+        ErrorKind::Interrupted => StatusCode::CONFLICT,
+        ErrorKind::PermissionDenied => StatusCode::PRECONDITION_FAILED,
+        ErrorKind::InvalidData => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
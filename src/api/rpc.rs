@@ -1,28 +1,53 @@
+use std::io::ErrorKind;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::RwLock;
 
+use bytes::Bytes;
 use chrono::prelude::*;
-use lz4_compress::{compress, decompress};
-use tonic::{Request, Response, Status, transport::Server};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
 use uuid::Uuid;
 
-use block_api::{IdxReply, IdxRequest};
+use block_api::block_api_server::{BlockApi, BlockApiServer};
+use block_api::WriteOptions;
+use block_api::{batch_get_reply, BatchDeleteReply, BatchExistsReply, BatchGetReply};
+use block_api::{list_corrupt_reply, ListCorruptReply, ListCorruptRequest};
+use block_api::{list_object_blocks_reply, ListObjectBlocksReply, ListObjectBlocksRequest};
 use block_api::{status_reply, StatusReply, StatusRequest};
+use block_api::{AcknowledgeCorruptReply, AcknowledgeCorruptRequest};
+use block_api::{AppendReply, AppendRequest};
+use block_api::{BatchGetRequest, BatchRequest};
+use block_api::{DeleteObjectReply, DeleteObjectRequest};
+use block_api::{DeleteReply, DeleteRequest};
 use block_api::{ExistsReply, ExistsRequest};
+use block_api::{GetReply, GetRequest};
+use block_api::{GetStreamReply, PutStreamReply, PutStreamRequest};
+use block_api::{IdxReply, IdxRequest};
 use block_api::{InsertReply, InsertRequest};
+use block_api::{ListReply, ListRequest};
+use block_api::{UndeleteReply, UndeleteRequest};
+use block_api::{UpdateMetaReply, UpdateMetaRequest};
 use block_api::{UpsertReply, UpsertRequest};
-use block_api::{GetReply, GetRequest};
-use block_api::{AppendReply, AppendRequest};
-use block_api::{DeleteReply, DeleteRequest};
-use block_api::block_api_server::{BlockApi, BlockApiServer};
 
-use crate::config::Config;
+use crate::config::{Config, CorruptRead};
 use crate::metrics::{GRPC_BYTES_IN, GRPC_BYTES_OUT, GRPC_COUNTER, GRPC_REQ_HISTOGRAM};
-use crate::stora::disk::{DISK, mark_block_as_deleted, read_block};
-use crate::stora::meta::BlockMeta;
-use crate::stora::meta::HashFun::{Hgw128, Hgw256, Md5, Other, Sha128, Sha256};
+use crate::stora::disk::{
+    append_block, erase_block, mark_block_as_deleted, mark_blocks_as_deleted,
+    mark_object_as_deleted, stream_block, undelete_block, write_block,
+};
+use crate::stora::meta::HashFun::{Hgw128, Hgw256, Md5, Sha128, Sha256};
+use crate::stora::meta::{BlockFilter, BlockMeta, CorruptBlock, HashFun, Precondition};
 use crate::stora::status::Status as SysStatus;
 
+/// Blocks read from the metadb at once by the List stream.
+const LIST_PAGE_SIZE: usize = 256;
+/// Block ids accepted by the batch calls.
+const BATCH_SIZE_MAX: usize = 1000;
+/// Payload bytes returned by BatchGet, the default grpc message limit.
+const BATCH_PAYLOAD_MAX: usize = 4 * 1024 * 1024;
+
 lazy_static! {
     pub static ref CONFIG: RwLock<Option<Config>> = RwLock::new(None);
 }
@@ -56,8 +81,20 @@ impl BlockApi for MyBlockApi {
                 timer.observe_duration();
                 return Err(tonic::Status::invalid_argument("Block id is required"));
             }
-            bid => bid.to_string()
+            bid => bid.to_string(),
         };
+        if request.options.map_or(false, |o| o.direct) {
+            let res = match erase_block(&block_id) {
+                Ok(true) => Ok(Response::new(DeleteReply { block_id: block_id })),
+                Ok(false) => Err(tonic::Status::not_found("Block id is not found")),
+                Err(e) => {
+                    error!("can't erase block: {}", e);
+                    Err(tonic::Status::internal("Disk issue on this machine"))
+                }
+            };
+            timer.observe_duration();
+            return res;
+        }
         match BlockMeta::get(block_id) {
             Ok(Some(meta)) => {
                 let deleted_bid = meta.id.to_owned();
@@ -78,6 +115,76 @@ impl BlockApi for MyBlockApi {
         }
     }
     // ---------------------------------------------------------------------------------------------
+    async fn delete_object(
+        &self,
+        request: Request<DeleteObjectRequest>,
+    ) -> Result<Response<DeleteObjectReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["delete_object"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let object_id = match request.into_inner().object_id.as_str() {
+            "" => {
+                timer.observe_duration();
+                return Err(tonic::Status::invalid_argument("Object id is required"));
+            }
+            oid => oid.to_string(),
+        };
+        match mark_object_as_deleted(&object_id) {
+            Ok(block_ids) if block_ids.is_empty() => {
+                timer.observe_duration();
+                Err(tonic::Status::not_found("Object id is not found"))
+            }
+            Ok(block_ids) => {
+                timer.observe_duration();
+                Ok(Response::new(DeleteObjectReply {
+                    object_id: object_id,
+                    block_ids: block_ids,
+                }))
+            }
+            Err(_) => {
+                timer.observe_duration();
+                Err(tonic::Status::internal("Metadb issue"))
+            }
+        }
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn undelete(
+        &self,
+        request: Request<UndeleteRequest>,
+    ) -> Result<Response<UndeleteReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["undelete"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let block_id = match request.into_inner().block_id.as_str() {
+            "" => {
+                timer.observe_duration();
+                return Err(tonic::Status::invalid_argument("Block id is required"));
+            }
+            bid => bid.to_string(),
+        };
+        let res = match undelete_block(&block_id) {
+            Ok(meta) => Ok(Response::new(UndeleteReply {
+                block_id: meta.id.clone(),
+                object_id: meta.object_id.clone(),
+                meta: Some(meta.to_grpc()),
+            })),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                Err(tonic::Status::not_found("Block id is not in the trash"))
+            }
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                Err(tonic::Status::already_exists("Block id is written again"))
+            }
+            Err(e) => {
+                error!("can't undelete block: {}", e);
+                Err(tonic::Status::internal("Metadb issue"))
+            }
+        };
+        timer.observe_duration();
+        res
+    }
+    // ---------------------------------------------------------------------------------------------
     async fn append(
         &self,
         request: Request<AppendRequest>,
@@ -92,19 +199,32 @@ impl BlockApi for MyBlockApi {
                 timer.observe_duration();
                 return Err(tonic::Status::invalid_argument("Block id is required"));
             }
-            bid => bid.to_string()
+            bid => bid.to_string(),
         };
         let payload = request.payload;
 
         GRPC_BYTES_IN.inc_by(payload.len() as f64);
 
-        if payload.len() > CONFIG.read().unwrap().clone().unwrap().storage.block_size_limit_bytes as usize
+        if payload.len()
+            > CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_size_limit_bytes as usize
         {
             timer.observe_duration();
             return Err(tonic::Status::resource_exhausted("Payload too large"));
         }
-        match BlockMeta::append(block_id, payload) {
-            Ok(Some(meta)) => {
+        let size = payload.len() as u64;
+        let precondition = expected_crc(request.expected_crc);
+        let hash = request
+            .options
+            .map(|options| options.hash)
+            .unwrap_or_default();
+        match append_block(&block_id, size, payload_parts(payload), hash, precondition).await {
+            Ok(meta) => {
                 timer.observe_duration();
                 Ok(Response::new(AppendReply {
                     block_id: meta.id.clone(),
@@ -112,20 +232,15 @@ impl BlockApi for MyBlockApi {
                     meta: Some(meta.to_grpc()),
                 }))
             }
-            _ => {
+            Err(e) => {
                 timer.observe_duration();
-                Err(tonic::Status::not_found("Block id is not found"))
+                Err(write_status(e))
             }
         }
     }
     // ---------------------------------------------------------------------------------------------
-    async fn get(
-        &self,
-        request: Request<GetRequest>,
-    ) -> Result<Response<GetReply>, Status> {
-        let timer = GRPC_REQ_HISTOGRAM
-            .with_label_values(&["get"])
-            .start_timer();
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM.with_label_values(&["get"]).start_timer();
         GRPC_COUNTER.inc();
         let request = request.into_inner();
         let block_id = match request.block_id.as_str() {
@@ -133,12 +248,19 @@ impl BlockApi for MyBlockApi {
                 timer.observe_duration();
                 return Err(tonic::Status::invalid_argument("Block id is required"));
             }
-            bid => bid.to_string()
+            bid => bid.to_string(),
         };
         let crc = request.crc.as_str().to_string();
         let lz4_transfer = request.allow_compressed;
         match BlockMeta::get(block_id) {
             Ok(Some(meta)) => {
+                let warning = match corrupt_warning(&meta) {
+                    Ok(warning) => warning,
+                    Err(e) => {
+                        timer.observe_duration();
+                        return Err(e);
+                    }
+                };
                 if !crc.eq("") && crc.eq(&meta.crc) {
                     timer.observe_duration();
                     return Ok(Response::new(GetReply {
@@ -150,14 +272,20 @@ impl BlockApi for MyBlockApi {
                         meta: None,
                     }));
                 }
-                let body = match read_block(&meta.path) {
-                    Ok(content) => {
-                        if meta.compressed && !lz4_transfer {
-                            decompress(content.as_slice()).unwrap()
-                        } else {
-                            content
+                let (offset, length, decompressed) =
+                    match block_range(&meta, request.offset, request.length, lz4_transfer) {
+                        Ok(range) => range,
+                        Err(e) => {
+                            timer.observe_duration();
+                            return Err(e);
                         }
-                    }
+                    };
+                let body = match stream_block(&meta, offset, length, decompressed) {
+                    Ok(parts) => parts.try_concat().await,
+                    Err(e) => Err(e),
+                };
+                let body = match body {
+                    Ok(body) => body,
                     Err(e) => {
                         error!("can't read block: {}", e);
                         timer.observe_duration();
@@ -166,14 +294,17 @@ impl BlockApi for MyBlockApi {
                 };
                 GRPC_BYTES_OUT.inc_by(body.len() as f64);
                 timer.observe_duration();
-                Ok(Response::new(GetReply {
-                    block_id: meta.id.clone(),
-                    object_id: meta.object_id.clone(),
-                    payload: body,
-                    not_modified: false,
-                    compressed: !(meta.compressed && !lz4_transfer),
-                    meta: Some(meta.to_grpc()),
-                }))
+                Ok(with_warning(
+                    GetReply {
+                        block_id: meta.id.clone(),
+                        object_id: meta.object_id.clone(),
+                        payload: body,
+                        not_modified: false,
+                        compressed: meta.compressed && !decompressed,
+                        meta: Some(meta.to_grpc()),
+                    },
+                    warning,
+                ))
             }
             _ => {
                 timer.observe_duration();
@@ -193,93 +324,41 @@ impl BlockApi for MyBlockApi {
         let request = request.into_inner();
         let block_id = match request.block_id.as_str() {
             "" => format!("{}", Uuid::new_v4().to_simple()),
-            bid => bid.to_string()
+            bid => bid.to_string(),
         };
         let object_id = request.object_id;
         let payload = request.payload;
-        if payload.len() > CONFIG.read().unwrap().clone().unwrap().storage.block_size_limit_bytes as usize
+        if payload.len()
+            > CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_size_limit_bytes as usize
         {
             timer.observe_duration();
             return Err(tonic::Status::resource_exhausted("Payload too large"));
         }
 
-        let mut b = BlockMeta::new();
-        b.id = block_id.to_owned();
-        b.object_id = object_id.to_owned();
-        b.size = payload.len() as u64;
-        b.orig_size = b.size;
-        b.last_check_ts = Utc::now().timestamp() as u64;
-        match request.options {
-            Some(options) => {
-                b.content_type = options.content_type;
-                b.compressed = options.compress;
-                b.hash = options.hash;
-                b.hash_fun = match options.hash_fun {
-                    1 => Md5,
-                    2 => Sha128,
-                    3 => Sha256,
-                    4 => Hgw128,
-                    5 => Hgw256,
-                    _ => Other,
-                };
-            }
-            _ => {
-                // without opts => skip
-                ()
-            }
-        }
+        let precondition = expected_crc(request.expected_crc);
+        let b = new_block_meta(&block_id, &object_id, payload.len() as u64, request.options);
 
-        GRPC_BYTES_IN.inc_by(b.size as f64);
-
-        let slot = { DISK.write().unwrap().get_write_slot() };
-        match slot {
-            Ok(slot) => {
-                let body = if b.compressed {
-                    let compressed_body = compress(&payload);
-                    if compressed_body.len() < payload.len() {
-                        b.size = compressed_body.len() as u64;
-                        b.compressed = true;
-                        compressed_body
-                    } else {
-                        b.compressed = false;
-                        payload
-                    }
-                } else {
-                    payload
-                };
+        GRPC_BYTES_IN.inc_by(b.orig_size as f64);
 
-                match slot.clone().store(body.to_vec()) {
-                    Ok(saved_file) => {
-                        b.volume_id = slot.volume_id.to_owned();
-                        b.bucket_id = slot.bucket_id.to_owned();
-                        b.path = saved_file;
-                        b.crc = BlockMeta::crc(body.to_vec());
-                        let bc = b.clone().to_owned();
-                        if let Err(_) = slot.commit(b) {
-                            error!("can't commit slot");
-                            timer.observe_duration();
-                            return Err(tonic::Status::internal("Disk can't write payload"));
-                        } else {
-                            timer.observe_duration();
-                            Ok(Response::new(UpsertReply {
-                                block_id: block_id.clone(),
-                                object_id: object_id.clone(),
-                                meta: Some(bc.to_grpc()),
-                            }))
-                        }
-                    }
-                    Err(e) => {
-                        slot.release(0);
-                        error!("can't write payload {}", e);
-                        timer.observe_duration();
-                        return Err(tonic::Status::internal("Disk can't write payload"));
-                    }
-                }
+        match write_block(b, payload_parts(payload), precondition).await {
+            Ok(meta) => {
+                timer.observe_duration();
+                Ok(Response::new(UpsertReply {
+                    block_id: block_id.clone(),
+                    object_id: object_id.clone(),
+                    meta: Some(meta.to_grpc()),
+                }))
             }
-            Err(_) => {
-                error!("disk get slot");
+            Err(e) => {
+                error!("can't write payload {}", e);
                 timer.observe_duration();
-                return Err(tonic::Status::internal("Disk get slot issue"));
+                Err(write_status(e))
             }
         }
     }
@@ -295,11 +374,18 @@ impl BlockApi for MyBlockApi {
         let request = request.into_inner();
         let block_id = match request.block_id.as_str() {
             "" => format!("{}", Uuid::new_v4().to_simple()),
-            bid => bid.to_string()
+            bid => bid.to_string(),
         };
         let object_id = request.object_id;
         let payload = request.payload;
-        if payload.len() > CONFIG.read().unwrap().clone().unwrap().storage.block_size_limit_bytes as usize
+        if payload.len()
+            > CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_size_limit_bytes as usize
         {
             timer.observe_duration();
             return Err(tonic::Status::resource_exhausted("Payload too large"));
@@ -309,87 +395,210 @@ impl BlockApi for MyBlockApi {
             return Err(tonic::Status::already_exists("Object with this id exists"));
         }
 
-        let mut b = BlockMeta::new();
-        b.id = block_id.to_owned();
-        b.object_id = object_id.to_owned();
-        b.size = payload.len() as u64;
-        b.orig_size = b.size;
-        b.last_check_ts = Utc::now().timestamp() as u64;
-        match request.options {
-            Some(options) => {
-                b.content_type = options.content_type;
-                b.compressed = options.compress;
-                b.hash = options.hash;
-                b.hash_fun = match options.hash_fun {
-                    1 => Md5,
-                    2 => Sha128,
-                    3 => Sha256,
-                    4 => Hgw128,
-                    5 => Hgw256,
-                    _ => Other,
-                };
+        let b = new_block_meta(&block_id, &object_id, payload.len() as u64, request.options);
+
+        GRPC_BYTES_IN.inc_by(b.orig_size as f64);
+
+        match write_block(b, payload_parts(payload), Precondition::Absent).await {
+            Ok(meta) => {
+                timer.observe_duration();
+                Ok(Response::new(InsertReply {
+                    block_id: block_id.clone(),
+                    object_id: object_id.clone(),
+                    meta: Some(meta.to_grpc()),
+                }))
             }
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                timer.observe_duration();
+                Err(tonic::Status::already_exists("Object with this id exists"))
+            }
+            Err(e) => {
+                error!("can't write payload {}", e);
+                timer.observe_duration();
+                Err(write_status(e))
+            }
+        }
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn put_stream(
+        &self,
+        request: Request<Streaming<PutStreamRequest>>,
+    ) -> Result<Response<PutStreamReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["put_stream"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let mut frames = request.into_inner();
+        let head = match frames.message().await {
+            Ok(Some(head)) => head,
             _ => {
-                // without opts => skip
-                ()
+                timer.observe_duration();
+                return Err(tonic::Status::invalid_argument(
+                    "Block description is required",
+                ));
+            }
+        };
+        let block_id = match head.block_id.as_str() {
+            "" => format!("{}", Uuid::new_v4().to_simple()),
+            bid => bid.to_string(),
+        };
+        let object_id = head.object_id;
+        if head.size
+            > CONFIG
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap()
+                .storage
+                .block_size_limit_bytes
+        {
+            timer.observe_duration();
+            return Err(tonic::Status::resource_exhausted("Payload too large"));
+        }
+        if !head.upsert {
+            if let Ok(true) = BlockMeta::exists(block_id.clone()) {
+                timer.observe_duration();
+                return Err(tonic::Status::already_exists("Object with this id exists"));
             }
         }
 
-        GRPC_BYTES_IN.inc_by(b.size as f64);
-
-        let slot = { DISK.write().unwrap().get_write_slot() };
-        match slot {
-            Ok(slot) => {
-                let body = if b.compressed {
-                    let compressed_body = compress(&payload);
-                    if compressed_body.len() < payload.len() {
-                        b.size = compressed_body.len() as u64;
-                        b.compressed = true;
-                        compressed_body
-                    } else {
-                        b.compressed = false;
-                        payload
-                    }
-                } else {
-                    payload
-                };
-
-                match slot.clone().store(body.to_vec()) {
-                    Ok(saved_file) => {
-                        b.volume_id = slot.volume_id.to_owned();
-                        b.bucket_id = slot.bucket_id.to_owned();
-                        b.path = saved_file;
-                        b.crc = BlockMeta::crc(body.to_vec());
-                        let bc = b.clone().to_owned();
-                        if let Err(_) = slot.commit(b) {
-                            error!("can't commit slot");
-                            timer.observe_duration();
-                            return Err(tonic::Status::internal("Disk can't write payload"));
-                        } else {
-                            timer.observe_duration();
-                            Ok(Response::new(InsertReply {
-                                block_id: block_id.clone(),
-                                object_id: object_id.clone(),
-                                meta: Some(bc.to_grpc()),
-                            }))
-                        }
-                    }
-                    Err(e) => {
-                        slot.release(0);
-                        error!("can't write payload {}", e);
-                        timer.observe_duration();
-                        return Err(tonic::Status::internal("Disk can't write payload"));
-                    }
+        let precondition = match head.upsert {
+            true => expected_crc(head.expected_crc),
+            false => Precondition::Absent,
+        };
+        let b = new_block_meta(&block_id, &object_id, head.size, head.options);
+        let parts = stream::once(future::ready(Ok(head.payload)))
+            .chain(frames.map(|frame| frame.map(|frame| frame.payload)))
+            .map(|part| match part {
+                Ok(part) => {
+                    GRPC_BYTES_IN.inc_by(part.len() as f64);
+                    Ok(Bytes::from(part))
                 }
+                Err(e) => Err(e.to_string()),
+            });
+
+        match write_block(b, Box::pin(parts), precondition).await {
+            Ok(meta) => {
+                timer.observe_duration();
+                Ok(Response::new(PutStreamReply {
+                    block_id: block_id.clone(),
+                    object_id: object_id.clone(),
+                    meta: Some(meta.to_grpc()),
+                }))
             }
-            Err(_) => {
-                error!("disk get slot");
+            Err(e) => {
+                error!("can't write payload {}", e);
                 timer.observe_duration();
-                return Err(tonic::Status::internal("Disk get slot issue"));
+                Err(write_status(e))
             }
         }
     }
     // ---------------------------------------------------------------------------------------------
+    type GetStreamStream = mpsc::Receiver<Result<GetStreamReply, Status>>;
+
+    async fn get_stream(
+        &self,
+        request: Request<GetRequest>,
+    ) -> Result<Response<Self::GetStreamStream>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["get_stream"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let request = request.into_inner();
+        let block_id = match request.block_id.as_str() {
+            "" => {
+                timer.observe_duration();
+                return Err(tonic::Status::invalid_argument("Block id is required"));
+            }
+            bid => bid.to_string(),
+        };
+        let crc = request.crc.as_str().to_string();
+        let lz4_transfer = request.allow_compressed;
+        let meta = match BlockMeta::get(block_id) {
+            Ok(Some(meta)) => meta,
+            _ => {
+                timer.observe_duration();
+                return Err(tonic::Status::not_found("Block id is not found"));
+            }
+        };
+        let warning = match corrupt_warning(&meta) {
+            Ok(warning) => warning,
+            Err(e) => {
+                timer.observe_duration();
+                return Err(e);
+            }
+        };
+        let (mut tx, rx) = mpsc::channel(4);
+        if !crc.eq("") && crc.eq(&meta.crc) {
+            let _ = tx
+                .send(Ok(GetStreamReply {
+                    block_id: meta.id,
+                    object_id: meta.object_id,
+                    payload: vec![],
+                    compressed: false,
+                    not_modified: true,
+                    meta: None,
+                }))
+                .await;
+            timer.observe_duration();
+            return Ok(Response::new(rx));
+        }
+
+        let (offset, length, decompressed) =
+            match block_range(&meta, request.offset, request.length, lz4_transfer) {
+                Ok(range) => range,
+                Err(e) => {
+                    timer.observe_duration();
+                    return Err(e);
+                }
+            };
+        let parts = stream_block(&meta, offset, length, decompressed);
+        let mut parts = match parts {
+            Ok(parts) => parts,
+            Err(e) => {
+                error!("can't read block: {}", e);
+                timer.observe_duration();
+                return Err(tonic::Status::unavailable("Disk issue on this machine"));
+            }
+        };
+
+        tokio::spawn(async move {
+            let mut head = Some(meta.to_grpc());
+            loop {
+                let part = match parts.next().await {
+                    Some(Ok(part)) => part,
+                    Some(Err(e)) => {
+                        error!("can't read block: {}", e);
+                        let _ = tx
+                            .send(Err(tonic::Status::unavailable(
+                                "Disk issue on this machine",
+                            )))
+                            .await;
+                        break;
+                    }
+                    // an empty block still gets the frame with meta
+                    None if head.is_some() => vec![],
+                    None => break,
+                };
+                GRPC_BYTES_OUT.inc_by(part.len() as f64);
+                let frame = GetStreamReply {
+                    block_id: meta.id.clone(),
+                    object_id: meta.object_id.clone(),
+                    payload: part,
+                    compressed: meta.compressed && !decompressed,
+                    not_modified: false,
+                    meta: head.take(),
+                };
+                if let Err(_) = tx.send(Ok(frame)).await {
+                    // client is gone
+                    break;
+                }
+            }
+            timer.observe_duration();
+        });
+        Ok(with_warning(rx, warning))
+    }
+    // ---------------------------------------------------------------------------------------------
     async fn exists(
         &self,
         request: Request<ExistsRequest>,
@@ -401,19 +610,352 @@ impl BlockApi for MyBlockApi {
         let block_id = request.into_inner().block_id;
         let found = BlockMeta::exists(block_id) == Ok(true);
         timer.observe_duration();
-        Ok(Response::new(ExistsReply {
-            found: found,
-        }))
+        Ok(Response::new(ExistsReply { found: found }))
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn update_meta(
+        &self,
+        request: Request<UpdateMetaRequest>,
+    ) -> Result<Response<UpdateMetaReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["update_meta"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let request = request.into_inner();
+        let block_id = match request.block_id.as_str() {
+            "" => {
+                timer.observe_duration();
+                return Err(tonic::Status::invalid_argument("Block id is required"));
+            }
+            bid => bid.to_string(),
+        };
+        let mut meta = match BlockMeta::get(block_id) {
+            Ok(Some(meta)) => meta,
+            _ => {
+                timer.observe_duration();
+                return Err(tonic::Status::not_found("Block id is not found"));
+            }
+        };
+        if !request.crc.eq("") && !request.crc.eq(&meta.crc) {
+            timer.observe_duration();
+            return Err(tonic::Status::failed_precondition("Crc mismatch"));
+        }
+        if !request.object_id.eq("") {
+            meta.object_id = request.object_id;
+        }
+        if !request.content_type.eq("") {
+            meta.content_type = request.content_type;
+        }
+        meta.merge_user_meta(request.user_meta, request.replace_user_meta);
+        // the payload location is compared on update, so the crc can't change in between
+        match meta.update() {
+            Ok(_) => {
+                timer.observe_duration();
+                Ok(Response::new(UpdateMetaReply {
+                    block_id: meta.id.clone(),
+                    object_id: meta.object_id.clone(),
+                    meta: Some(meta.to_grpc()),
+                }))
+            }
+            Err(e) => {
+                timer.observe_duration();
+                Err(write_status(e))
+            }
+        }
     }
     // ---------------------------------------------------------------------------------------------
-    async fn idx(
+    async fn list_object_blocks(
         &self,
-        _request: Request<IdxRequest>,
-    ) -> Result<Response<IdxReply>, Status> {
+        request: Request<ListObjectBlocksRequest>,
+    ) -> Result<Response<ListObjectBlocksReply>, Status> {
         let timer = GRPC_REQ_HISTOGRAM
-            .with_label_values(&["idx"])
+            .with_label_values(&["list_object_blocks"])
             .start_timer();
         GRPC_COUNTER.inc();
+        let object_id = match request.into_inner().object_id.as_str() {
+            "" => {
+                timer.observe_duration();
+                return Err(tonic::Status::invalid_argument("Object id is required"));
+            }
+            oid => oid.to_string(),
+        };
+        match BlockMeta::fetch_by_object(&object_id) {
+            Ok(blocks) => {
+                timer.observe_duration();
+                Ok(Response::new(ListObjectBlocksReply {
+                    object_id: object_id,
+                    blocks: blocks
+                        .iter()
+                        .map(|meta| list_object_blocks_reply::Block {
+                            block_id: meta.id.clone(),
+                            meta: Some(meta.to_grpc()),
+                        })
+                        .collect(),
+                }))
+            }
+            Err(_) => {
+                timer.observe_duration();
+                Err(tonic::Status::internal("Metadb issue"))
+            }
+        }
+    }
+    // ---------------------------------------------------------------------------------------------
+    type ListStream = mpsc::Receiver<Result<ListReply, Status>>;
+
+    async fn list(
+        &self,
+        request: Request<ListRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["list"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let request = request.into_inner();
+        let filter = BlockFilter {
+            volume_id: Some(request.volume_id).filter(|v| !v.is_empty()),
+            bucket_ids: request.bucket_ids,
+            content_type: Some(request.content_type).filter(|c| !c.is_empty()),
+            created_from: request
+                .created
+                .as_ref()
+                .map(|r| r.from)
+                .filter(|ts| *ts > 0),
+            created_to: request.created.as_ref().map(|r| r.to).filter(|ts| *ts > 0),
+            size_min: request
+                .size
+                .as_ref()
+                .map(|r| r.from)
+                .filter(|size| *size > 0),
+            size_max: request.size.as_ref().map(|r| r.to).filter(|size| *size > 0),
+        };
+        let mut left = match request.limit {
+            0 => usize::max_value(),
+            limit => limit as usize,
+        };
+        let mut cursor = request.cursor;
+
+        let (mut tx, rx) = mpsc::channel(LIST_PAGE_SIZE);
+        tokio::spawn(async move {
+            while left > 0 {
+                let page_size = std::cmp::min(left, LIST_PAGE_SIZE);
+                let (blocks, next) = match BlockMeta::list(&filter, &cursor, page_size) {
+                    Ok(page) => page,
+                    Err(_) => {
+                        let _ = tx.send(Err(tonic::Status::internal("Metadb issue"))).await;
+                        break;
+                    }
+                };
+                left -= blocks.len();
+                for meta in blocks {
+                    let frame = ListReply {
+                        block_id: meta.id.clone(),
+                        object_id: meta.object_id.clone(),
+                        meta: Some(meta.to_grpc()),
+                    };
+                    if let Err(_) = tx.send(Ok(frame)).await {
+                        // client is gone
+                        left = 0;
+                        break;
+                    }
+                }
+                match next {
+                    Some(next) => cursor = next,
+                    None => break,
+                }
+            }
+            timer.observe_duration();
+        });
+        Ok(Response::new(rx))
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn batch_exists(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<BatchExistsReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["batch_exists"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let block_ids = request.into_inner().block_ids;
+        if block_ids.len() > BATCH_SIZE_MAX {
+            timer.observe_duration();
+            return Err(tonic::Status::invalid_argument("Too many block ids"));
+        }
+        match BlockMeta::get_many(&block_ids) {
+            Ok(metas) => {
+                timer.observe_duration();
+                Ok(Response::new(BatchExistsReply {
+                    found: metas.iter().map(|meta| meta.is_some()).collect(),
+                }))
+            }
+            Err(_) => {
+                timer.observe_duration();
+                Err(tonic::Status::internal("Metadb issue"))
+            }
+        }
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<Response<BatchGetReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["batch_get"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let request = request.into_inner();
+        if request.block_ids.len() > BATCH_SIZE_MAX {
+            timer.observe_duration();
+            return Err(tonic::Status::invalid_argument("Too many block ids"));
+        }
+        let metas = match BlockMeta::get_many(&request.block_ids) {
+            Ok(metas) => metas,
+            Err(_) => {
+                timer.observe_duration();
+                return Err(tonic::Status::internal("Metadb issue"));
+            }
+        };
+        let mut items: Vec<batch_get_reply::Item> = Vec::with_capacity(metas.len());
+        let mut payload_bytes: usize = 0;
+        for (block_id, meta) in request.block_ids.iter().zip(metas.into_iter()) {
+            let mut item = batch_get_reply::Item {
+                block_id: block_id.to_owned(),
+                object_id: "".to_string(),
+                payload: vec![],
+                compressed: false,
+                meta: None,
+                error: "".to_string(),
+            };
+            let meta = match meta {
+                Some(meta) => meta,
+                None => {
+                    item.error = "Block id is not found".to_string();
+                    items.push(item);
+                    continue;
+                }
+            };
+            item.object_id = meta.object_id.clone();
+            item.meta = Some(meta.to_grpc());
+            if let Err(e) = corrupt_warning(&meta) {
+                item.error = e.message().to_string();
+                items.push(item);
+                continue;
+            }
+            if !request.omit_payload {
+                let (offset, length, decompressed) =
+                    block_range(&meta, 0, 0, request.allow_compressed)?;
+                if payload_bytes + length as usize > BATCH_PAYLOAD_MAX {
+                    item.error = "Batch payload limit exceeded".to_string();
+                    items.push(item);
+                    continue;
+                }
+                let body = match stream_block(&meta, offset, length, decompressed) {
+                    Ok(parts) => parts.try_concat().await,
+                    Err(e) => Err(e),
+                };
+                match body {
+                    Ok(body) => {
+                        payload_bytes += body.len();
+                        item.payload = body;
+                        item.compressed = meta.compressed && !decompressed;
+                    }
+                    Err(e) => {
+                        error!("can't read block: {}", e);
+                        item.error = "Disk issue on this machine".to_string();
+                    }
+                }
+            }
+            items.push(item);
+        }
+        GRPC_BYTES_OUT.inc_by(payload_bytes as f64);
+        timer.observe_duration();
+        Ok(Response::new(BatchGetReply { items: items }))
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn batch_delete(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<BatchDeleteReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["batch_delete"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let block_ids = request.into_inner().block_ids;
+        if block_ids.len() > BATCH_SIZE_MAX {
+            timer.observe_duration();
+            return Err(tonic::Status::invalid_argument("Too many block ids"));
+        }
+        match mark_blocks_as_deleted(&block_ids) {
+            Ok(deleted) => {
+                timer.observe_duration();
+                Ok(Response::new(BatchDeleteReply { block_ids: deleted }))
+            }
+            Err(_) => {
+                timer.observe_duration();
+                Err(tonic::Status::internal("Metadb issue"))
+            }
+        }
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn list_corrupt(
+        &self,
+        request: Request<ListCorruptRequest>,
+    ) -> Result<Response<ListCorruptReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["list_corrupt"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let request = request.into_inner();
+        let limit = match request.limit {
+            0 => LIST_PAGE_SIZE,
+            limit => std::cmp::min(limit as usize, BATCH_SIZE_MAX),
+        };
+        match CorruptBlock::list(&request.cursor, limit) {
+            Ok((entries, next)) => {
+                timer.observe_duration();
+                Ok(Response::new(ListCorruptReply {
+                    entries: entries
+                        .into_iter()
+                        .map(|e| list_corrupt_reply::Entry {
+                            block_id: e.block_id,
+                            reason: e.reason.as_str().to_string(),
+                            details: e.details,
+                            detected: e.detected,
+                        })
+                        .collect(),
+                    cursor: next.unwrap_or_default(),
+                }))
+            }
+            Err(_) => {
+                timer.observe_duration();
+                Err(tonic::Status::internal("Metadb issue"))
+            }
+        }
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn acknowledge_corrupt(
+        &self,
+        request: Request<AcknowledgeCorruptRequest>,
+    ) -> Result<Response<AcknowledgeCorruptReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM
+            .with_label_values(&["acknowledge_corrupt"])
+            .start_timer();
+        GRPC_COUNTER.inc();
+        let block_id = request.into_inner().block_id;
+        let res = match CorruptBlock::acknowledge(&block_id) {
+            Ok(true) => Ok(Response::new(AcknowledgeCorruptReply {
+                block_id: block_id,
+            })),
+            Ok(false) => Err(tonic::Status::not_found("Block id is not quarantined")),
+            Err(_) => Err(tonic::Status::internal("Metadb issue")),
+        };
+        timer.observe_duration();
+        res
+    }
+    // ---------------------------------------------------------------------------------------------
+    async fn idx(&self, _request: Request<IdxRequest>) -> Result<Response<IdxReply>, Status> {
+        let timer = GRPC_REQ_HISTOGRAM.with_label_values(&["idx"]).start_timer();
+        GRPC_COUNTER.inc();
         timer.observe_duration();
         Ok(Response::new(IdxReply {
             message: "The little block engine that could!".into(),
@@ -479,12 +1021,133 @@ impl BlockApi for MyBlockApi {
                 tcp6_in_use: status.net.tcp6_in_use as u64,
                 udp6_in_use: status.net.udp6_in_use as u64,
             }),
+            validator: Some(status_reply::Validator {
+                position: status.validator.position,
+                pass_started: status.validator.pass_started,
+                scanned: status.validator.scanned,
+                checked: status.validator.checked,
+                blocks_per_sec: status.validator.blocks_per_sec,
+                eta_sec: status.validator.eta_sec,
+            }),
         };
         timer.observe_duration();
         Ok(Response::new(reply))
     }
 }
 
+fn new_block_meta(
+    block_id: &String,
+    object_id: &String,
+    size: u64,
+    options: Option<WriteOptions>,
+) -> BlockMeta {
+    let mut b = BlockMeta::new();
+    b.id = block_id.to_owned();
+    b.object_id = object_id.to_owned();
+    b.orig_size = size;
+    b.last_check_ts = Utc::now().timestamp() as u64;
+    match options {
+        Some(options) => {
+            b.content_type = options.content_type;
+            b.user_meta = options.user_meta;
+            b.compressed = options.compress;
+            b.hash = options.hash;
+            // OTHER is the proto default, so it doesn't hint the function
+            let hint = match options.hash_fun {
+                1 => Some(Md5),
+                2 => Some(Sha128),
+                3 => Some(Sha256),
+                4 => Some(Hgw128),
+                5 => Some(Hgw256),
+                _ => None,
+            };
+            b.hash_fun = HashFun::of_write(hint, &b.hash);
+            b.expires_at = match options.ttl {
+                0 => options.expires_at,
+                ttl => b.created + ttl,
+            };
+        }
+        _ => {
+            // without opts => skip
+            ()
+        }
+    }
+    b
+}
+
+// the range applies to the original bytes, so ranged reads of compressed blocks are decompressed
+fn block_range(
+    meta: &BlockMeta,
+    offset: u64,
+    length: u64,
+    allow_compressed: bool,
+) -> Result<(u64, u64, bool), Status> {
+    let ranged = offset > 0 || length > 0;
+    let decompressed = meta.compressed && (!allow_compressed || ranged);
+    let size = if meta.compressed && !decompressed {
+        meta.size
+    } else {
+        meta.orig_size
+    };
+    if offset > size {
+        return Err(tonic::Status::out_of_range("Offset is out of the block"));
+    }
+    let length = if length == 0 {
+        size - offset
+    } else {
+        std::cmp::min(length, size - offset)
+    };
+    Ok((offset, length, decompressed))
+}
+
+// a quarantined block is refused with data_loss or served with a warning
+fn corrupt_warning(meta: &BlockMeta) -> Result<Option<String>, Status> {
+    let entry = match CorruptBlock::quarantined(meta) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let warning = format!("block is quarantined: {}", entry.reason.as_str());
+    match CONFIG.read().unwrap().clone().unwrap().storage.corrupt_read {
+        CorruptRead::Warn => Ok(Some(warning)),
+        CorruptRead::Refuse => Err(tonic::Status::data_loss(warning)),
+    }
+}
+
+fn with_warning<T>(reply: T, warning: Option<String>) -> Response<T> {
+    let mut response = Response::new(reply);
+    if let Some(warning) = warning {
+        if let Ok(value) = warning.parse() {
+            response.metadata_mut().insert("warning", value);
+        }
+    }
+    response
+}
+
+// unary calls pass the whole payload as a single part
+fn payload_parts(payload: Vec<u8>) -> impl Stream<Item = Result<Bytes, String>> + Unpin {
+    stream::once(future::ready(Ok(Bytes::from(payload))))
+}
+
+// an empty expected crc writes unconditionally
+fn expected_crc(crc: String) -> Precondition {
+    match crc.as_str() {
+        "" => Precondition::Any,
+        _ => Precondition::Crc(crc),
+    }
+}
+
+fn write_status(e: std::io::Error) -> Status {
+    match e.kind() {
+        ErrorKind::InvalidInput => tonic::Status::invalid_argument("Payload size mismatch"),
+        ErrorKind::NotFound => tonic::Status::not_found("Block id is not found"),
+        ErrorKind::ConnectionAborted => tonic::Status::cancelled("Payload isn't received"),
+        ErrorKind::Interrupted => tonic::Status::aborted("Block was changed"),
+        ErrorKind::PermissionDenied => tonic::Status::failed_precondition("Crc mismatch"),
+        ErrorKind::InvalidData => tonic::Status::data_loss("Hash mismatch"),
+        _ => tonic::Status::internal("Disk can't write payload"),
+    }
+}
+
 #[derive(Debug)]
 pub struct BlockGrpcApi {
     pub endpoint: SocketAddr,
@@ -516,4 +1179,4 @@ impl BlockGrpcApi {
                 .await;
         });
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,17 @@
 use std::process;
 
 use clap::{crate_authors, crate_version, App, Arg};
-use log::{info, error};
+use log::{error, info};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::channel;
 
 use vstorage::api::rest::BlockRestApi;
 use vstorage::api::rpc::BlockGrpcApi;
 use vstorage::binutil::{self, cli_opts, setup};
+use vstorage::cluster::coordinator_api;
+use vstorage::cluster::CLUSTER;
 use vstorage::config::Config;
 use vstorage::stora::status::PhysStats;
-use vstorage::cluster::CLUSTER;
-use vstorage::cluster::coordinator_api;
 
 #[tokio::main]
 async fn main() {
@@ -63,8 +63,23 @@ async fn main() {
 
     let volumes = setup::bootstrap_volumes(&config);
     vstorage::stora::disk::init_volumes(volumes);
-    vstorage::stora::gc::process(config.storage.gc_batch, config.storage.gc_timeout_sec);
-    vstorage::stora::validator::process(config.storage.block_check_interval_days, 300);
+    vstorage::stora::gc::process(&config.storage);
+    vstorage::stora::expiry::process(
+        config.storage.expiry_sweep_batch,
+        config.storage.expiry_sweep_timeout_sec,
+    );
+    vstorage::stora::compactor::process(
+        config.storage.compaction_garbage_ratio,
+        config.storage.compaction_grace_sec,
+        config.storage.compaction_timeout_sec,
+    );
+    vstorage::stora::validator::process(
+        config.storage.block_check_interval_days,
+        config.storage.block_check_content_hash,
+        config.storage.block_check_batch,
+        config.storage.block_check_bytes_per_sec,
+        config.storage.block_check_timeout_sec,
+    );
 
     //init cluster
     if config.cluster.enabled {
@@ -81,16 +96,19 @@ async fn main() {
                 Ok(mut coordinator) => {
                     let pong = coordinator.ping().await.unwrap_or(false);
                     if pong {
-                        let registred = coordinator.register(coordinator_api::Server{
-                            endpoint: config.interfaces.rest.lan.clone(),
-                            nodename: config.node.nodename.clone(),
-                            zone: config.node.zone.clone(),
-                            rack: config.node.rack.clone(),
-                            srv_type: "storage".to_string(),
-                        }).await.unwrap_or(false);
+                        let registred = coordinator
+                            .register(coordinator_api::Server {
+                                endpoint: config.interfaces.rest.lan.clone(),
+                                nodename: config.node.nodename.clone(),
+                                zone: config.node.zone.clone(),
+                                rack: config.node.rack.clone(),
+                                srv_type: "storage".to_string(),
+                            })
+                            .await
+                            .unwrap_or(false);
                         if registred {
                             break;
-                        }else{
+                        } else {
                             error!("cluster: can't register");
                             std::thread::sleep(std::time::Duration::from_secs(1));
                         }
@@ -115,8 +133,7 @@ async fn main() {
 
     let grpc_lan_endpoint = config.interfaces.grpc.lan;
     if !grpc_lan_endpoint.eq("") {
-        BlockGrpcApi::new(&grpc_lan_endpoint, &"internal".to_string())
-            .serve();
+        BlockGrpcApi::new(&grpc_lan_endpoint, &"internal".to_string()).serve();
     }
 
     // public handlers
@@ -130,8 +147,7 @@ async fn main() {
 
     let grpc_wan_endpoint = config.interfaces.grpc.wan;
     if !grpc_wan_endpoint.eq("") {
-        BlockGrpcApi::new(&grpc_wan_endpoint, &"public".to_string())
-            .serve();
+        BlockGrpcApi::new(&grpc_wan_endpoint, &"public".to_string()).serve();
     }
 
     // init os signals handler
@@ -2,10 +2,15 @@ use clap::ArgMatches;
 use vm_util::collections::HashMap;
 
 use crate::config::Config;
+use crate::stora::bucket::Bucket;
+use crate::stora::compactor;
+use crate::stora::meta::{BlockMeta, Intent, IntentKind, LegacyBlockMeta};
+use crate::stora::segment::{abort_record, segment_path, SegmentBuilder};
 use crate::stora::volume::Volume;
 use rocksdb::{Options, DB};
 use std::fs;
 use std::io::Write;
+use std::path::Path;
 use std::process;
 use systemstat::{Platform, System};
 
@@ -44,6 +49,16 @@ pub fn init_metadb(config: &Config) -> DB {
     let _ = db.create_cf("blocks", &opts);
     let _ = db.create_cf("delete_queue", &opts);
     let _ = db.create_cf("move_queue", &opts);
+    let _ = db.create_cf("intents", &opts);
+    let _ = db.create_cf("object_index", &opts);
+    let _ = db.create_cf("corrupt", &opts);
+    let _ = db.create_cf("checkpoints", &opts);
+    let _ = db.create_cf("gc_retry", &opts);
+    let _ = db.create_cf("gc_retry_index", &opts);
+    let _ = db.create_cf("gc_dead_letter", &opts);
+    let _ = db.create_cf("trash", &opts);
+    let _ = db.create_cf("trash_index", &opts);
+    let _ = db.create_cf("expiry_index", &opts);
     db
 }
 
@@ -81,7 +96,12 @@ pub fn bootstrap_volumes(config: &Config) -> Vec<Volume> {
     let mut volumes: Vec<Volume> = vec![];
     for volume_path in config.storage.volumes.iter() {
         let mut volume = Volume::new(volume_path);
-        match volume.bootstrap(&mounts, config.storage.bucket_size_limit_bytes) {
+        match volume.bootstrap(
+            &mounts,
+            config.storage.bucket_size_limit_bytes,
+            config.storage.segment_size_limit_bytes,
+            &config.storage.durability,
+        ) {
             Ok(_v) => volumes.push(volume),
             Err(x) => {
                 error!("volume init: {}", x);
@@ -98,9 +118,219 @@ pub fn bootstrap_volumes(config: &Config) -> Vec<Volume> {
             }
         }
     }
+    recover_intents(&mut volumes);
+    migrate_legacy_blocks(&mut volumes);
     volumes
 }
 
+// writes interrupted by a crash are rolled back before the volumes are served
+fn recover_intents(volumes: &mut Vec<Volume>) {
+    let intents = match Intent::fetch_all() {
+        Ok(intents) => intents,
+        Err(e) => {
+            error!("can't fetch intents: {}", e);
+            process::exit(1)
+        }
+    };
+    if intents.len() > 0 {
+        info!("recover {} unfinished intents", intents.len());
+    }
+    for intent in intents {
+        let bucket = volumes
+            .iter_mut()
+            .find(|v| v.id.eq(&intent.volume_id))
+            .and_then(|v| v.buckets.iter_mut().find(|b| b.id == intent.bucket_id));
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => {
+                warn!("intent {}: unknown bucket, dropped", intent.db_id());
+                let _ = intent.delete();
+                continue;
+            }
+        };
+        match intent.kind {
+            IntentKind::Write => {
+                let committed = match BlockMeta::get_stored(intent.block_id.to_owned()) {
+                    Ok(Some(meta)) => meta.gc_key() == intent.db_id(),
+                    _ => false,
+                };
+                let path = segment_path(&bucket.path, intent.segment_id);
+                if committed || !Path::new(&path).exists() {
+                    info!("intent {}: nothing to recover", intent.db_id());
+                    let _ = intent.delete();
+                    continue;
+                }
+                if let Err(e) = abort_record(
+                    &bucket.path,
+                    intent.segment_id,
+                    intent.offset,
+                    &intent.block_id,
+                    intent.size,
+                ) {
+                    error!("intent {}: can't abort record: {}", intent.db_id(), e);
+                    continue;
+                }
+                if let Err(_) = intent.rollback() {
+                    error!("intent {}: can't rollback", intent.db_id());
+                    continue;
+                }
+                bucket.avail_size_bytes = bucket.avail_size_bytes.saturating_sub(intent.size);
+                bucket.gc_size_bytes += intent.size;
+                info!(
+                    "intent {}: orphan record of block {} rolled back, {} bytes left to compactor",
+                    intent.db_id(),
+                    intent.block_id,
+                    intent.size
+                );
+            }
+            IntentKind::Compact => {
+                compactor::rollback(
+                    &intent.volume_id,
+                    intent.bucket_id,
+                    &bucket.path,
+                    &vec![intent.segment_id],
+                );
+                info!(
+                    "intent {}: unfinished compaction output removed",
+                    intent.db_id()
+                );
+            }
+        }
+    }
+}
+
+// blocks stored as separate files by older versions are moved into segments once,
+// a crash in the middle leaves a compaction intent which is rolled back on the next start
+fn migrate_legacy_blocks(volumes: &mut Vec<Volume>) {
+    let deleted = match LegacyBlockMeta::fetch_all("delete_queue") {
+        Ok(deleted) => deleted,
+        Err(e) => {
+            error!("can't fetch legacy deleted blocks: {}", e);
+            process::exit(1)
+        }
+    };
+    for meta in deleted {
+        match fs::remove_file(&meta.path) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => {
+                error!(
+                    "legacy block {}: can't remove {}: {}",
+                    meta.id, meta.path, e
+                );
+                continue;
+            }
+        }
+        let bucket = volumes
+            .iter_mut()
+            .find(|v| v.id.eq(&meta.volume_id))
+            .and_then(|v| v.buckets.iter_mut().find(|b| b.id == meta.bucket_id));
+        let size = meta.size;
+        let id = meta.id.to_owned();
+        if let Err(_) = meta.purge() {
+            error!("legacy block {}: can't purge", id);
+            continue;
+        }
+        if let Some(bucket) = bucket {
+            bucket.gc_size_bytes = bucket.gc_size_bytes.saturating_sub(size);
+            bucket.avail_size_bytes += size;
+        }
+    }
+
+    let legacy = match LegacyBlockMeta::fetch_all("blocks") {
+        Ok(legacy) => legacy,
+        Err(e) => {
+            error!("can't fetch legacy blocks: {}", e);
+            process::exit(1)
+        }
+    };
+    if legacy.is_empty() {
+        return;
+    }
+    info!("migrate {} legacy blocks into segments", legacy.len());
+    for volume in volumes.iter() {
+        for bucket in volume.buckets.iter() {
+            let blocks: Vec<LegacyBlockMeta> = legacy
+                .iter()
+                .filter(|m| m.volume_id.eq(&volume.id) && m.bucket_id == bucket.id)
+                .cloned()
+                .collect();
+            if blocks.is_empty() {
+                continue;
+            }
+            if let Err(e) = migrate_bucket(volume, bucket, blocks) {
+                error!("can't migrate legacy blocks of {}: {}", bucket.path, e);
+                process::exit(1)
+            }
+        }
+    }
+}
+
+fn migrate_bucket(
+    volume: &Volume,
+    bucket: &Bucket,
+    blocks: Vec<LegacyBlockMeta>,
+) -> Result<(), String> {
+    let size_limit = bucket.writer.lock().unwrap().size_limit();
+    let mut pending = blocks.into_iter().peekable();
+    while pending.peek().is_some() {
+        let segment_id = bucket.writer.lock().unwrap().allocate();
+        if let Err(_) = Intent::compact(&volume.id, bucket.id, segment_id).store(true) {
+            return Err("can't store migration intent".to_string());
+        }
+        let mut builder = SegmentBuilder::create(&bucket.path, segment_id)?;
+        let mut moved: Vec<(LegacyBlockMeta, BlockMeta)> = vec![];
+        while let Some(meta) = pending.peek() {
+            if builder.size > 0 && builder.size + meta.size > size_limit {
+                break;
+            }
+            let meta = pending.next().unwrap();
+            let payload = match fs::read(&meta.path) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    builder.abort();
+                    compactor::rollback(&volume.id, bucket.id, &bucket.path, &vec![segment_id]);
+                    return Err(format!("block {}: {}", meta.id, e));
+                }
+            };
+            let offset = match builder.append(&meta.id, payload.as_slice()) {
+                Ok(offset) => offset,
+                Err(e) => {
+                    builder.abort();
+                    compactor::rollback(&volume.id, bucket.id, &bucket.path, &vec![segment_id]);
+                    return Err(e);
+                }
+            };
+            let migrated = meta.clone().into_block_meta(segment_id, offset);
+            moved.push((meta, migrated));
+        }
+        if let Err(e) = builder.finish() {
+            compactor::rollback(&volume.id, bucket.id, &bucket.path, &vec![segment_id]);
+            return Err(e);
+        }
+        let metas: Vec<BlockMeta> = moved.iter().map(|(_, m)| m.clone()).collect();
+        if let Err(_) = LegacyBlockMeta::migrate(&volume.id, bucket.id, segment_id, &metas) {
+            compactor::rollback(&volume.id, bucket.id, &bucket.path, &vec![segment_id]);
+            return Err("can't store migrated block meta".to_string());
+        }
+        for (legacy, _) in moved {
+            if let Err(e) = fs::remove_file(&legacy.path) {
+                warn!(
+                    "legacy block {}: can't remove {}: {}",
+                    legacy.id, legacy.path, e
+                );
+            }
+        }
+        info!(
+            "{} legacy blocks moved to segment {} of {}",
+            metas.len(),
+            segment_id,
+            bucket.path
+        );
+    }
+    Ok(())
+}
+
 fn validate_volumes(volume: &Volume, volumes: &Vec<Volume>) -> Result<bool, String> {
     for v in volumes.iter() {
         if v.path != volume.path && v.dev == volume.dev {
@@ -4,6 +4,7 @@ use std::io::Error as IoError;
 use std::io::Write;
 use std::path::Path;
 
+use chrono::NaiveTime;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
@@ -58,25 +59,89 @@ impl Config {
         //                "server_script can't be empty"
         //            ).into());
         //        }
+        self.storage.gc_windows()?;
         Ok(())
     }
 }
 
+/// How block writes are flushed to the disk before their meta is committed
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    None,
+    Fdatasync,
+    Fsync,
+}
+
+/// How reads of quarantined blocks are answered
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CorruptRead {
+    /// serve the block with a warning header
+    Warn,
+    /// refuse the read
+    Refuse,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
 pub struct Storage {
     pub block_size_limit_bytes: u64,
     pub bucket_size_limit_bytes: u64,
+    pub segment_size_limit_bytes: u64,
+    pub durability: Durability,
     pub volumes: Vec<String>,
     pub gc_timeout_sec: u32,
     pub gc_batch: u32,
+    /// failed purges are retried after gc-retry-backoff-sec doubled on every attempt,
+    /// the block goes to the dead-letter queue after gc-retry-limit attempts
+    pub gc_retry_limit: u32,
+    pub gc_retry_backoff_sec: u32,
+    /// local time windows like "22:00-06:00" when GC may purge, empty is any time
+    pub gc_windows: Vec<String>,
+    /// deleted blocks stay in the trash and can be undeleted for this long
+    pub delete_retention_sec: u32,
+    pub expiry_sweep_batch: u32,
+    pub expiry_sweep_timeout_sec: u32,
+    /// GC limits per volume, 0 is unlimited
+    pub gc_deletes_per_sec: u32,
+    pub gc_bytes_per_sec: u64,
+    /// GC backs off while the cpu iowait share is above, 0 disables the check
+    pub gc_iowait_threshold: f32,
+    pub compaction_garbage_ratio: f64,
+    pub compaction_grace_sec: u32,
+    pub compaction_timeout_sec: u32,
     pub block_check_interval_days: u32,
+    /// blocks scanned by the validator per tick
+    pub block_check_batch: u32,
+    pub block_check_timeout_sec: u32,
+    /// validator read limit per volume, 0 is unlimited
+    pub block_check_bytes_per_sec: u64,
+    /// validator also recomputes the client content hash of the original payload
+    pub block_check_content_hash: bool,
+    pub corrupt_read: CorruptRead,
 }
 
 impl Storage {
     fn default_volumes() -> Vec<String> {
         vec!["./info/data".to_string()]
     }
+
+    /// Parsed `gc_windows`, a window may wrap around midnight.
+    pub fn gc_windows(&self) -> Result<Vec<(NaiveTime, NaiveTime)>, String> {
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").ok();
+        self.gc_windows
+            .iter()
+            .map(|w| {
+                let mut bounds = w.splitn(2, '-');
+                match (bounds.next().and_then(parse), bounds.next().and_then(parse)) {
+                    (Some(from), Some(to)) => Ok((from, to)),
+                    _ => Err(format!("invalid gc window: {}", w)),
+                }
+            })
+            .collect()
+    }
 }
 
 impl Default for Storage {
@@ -85,9 +150,28 @@ impl Default for Storage {
             block_size_limit_bytes: 10 * 1024 * 1024,
             volumes: Storage::default_volumes(),
             bucket_size_limit_bytes: 1073741824,
+            segment_size_limit_bytes: 134217728,
+            durability: Durability::Fdatasync,
             gc_timeout_sec: 1,
             gc_batch: 1000,
+            gc_retry_limit: 10,
+            gc_retry_backoff_sec: 60,
+            gc_windows: vec![],
+            delete_retention_sec: 86400,
+            expiry_sweep_batch: 1000,
+            expiry_sweep_timeout_sec: 10,
+            gc_deletes_per_sec: 0,
+            gc_bytes_per_sec: 0,
+            gc_iowait_threshold: 0.3,
+            compaction_garbage_ratio: 0.3,
+            compaction_grace_sec: 300,
+            compaction_timeout_sec: 60,
             block_check_interval_days: 3,
+            block_check_batch: 1000,
+            block_check_timeout_sec: 1,
+            block_check_bytes_per_sec: 10 * 1024 * 1024,
+            block_check_content_hash: false,
+            corrupt_read: CorruptRead::Warn,
         }
     }
 }
@@ -143,7 +227,7 @@ pub struct Interfaces {
 impl Default for Interfaces {
     fn default() -> Interfaces {
         Interfaces {
-            rest: Interface{
+            rest: Interface {
                 wan: "[::1]:33088".to_string(),
                 lan: "[::1]:33087".to_string(),
             },
@@ -187,4 +271,4 @@ impl Default for Cluster {
             coordinators: vec!["[::1]:8800".to_string()],
         }
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
-use prometheus::{Counter, Gauge, GaugeVec, HistogramVec, IntGaugeVec};
+use prometheus::{Counter, Gauge, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec};
 
 lazy_static! {
     // ---------------------------------------------------------------------------------------------
@@ -66,6 +66,60 @@ lazy_static! {
         "GC time for deleting batch of blocks from disk (ms)."
     )).unwrap();
 
+    pub static ref GC_PURGED_COUNTER: Counter = register_counter!(opts!(
+        "gc_purged_blocks_total",
+        "Blocks purged by GC."
+    )).unwrap();
+
+    pub static ref GC_FAILED_COUNTER: Counter = register_counter!(opts!(
+        "gc_failed_purges_total",
+        "Failed GC purge attempts."
+    )).unwrap();
+
+    pub static ref GC_DEAD_LETTER_COUNTER: Counter = register_counter!(opts!(
+        "gc_dead_lettered_blocks_total",
+        "Blocks moved to the GC dead-letter queue after the retry limit."
+    )).unwrap();
+
+    pub static ref GC_IDLE_COUNTER: Counter = register_counter!(opts!(
+        "gc_idle_ticks_total",
+        "GC ticks skipped outside the GC windows."
+    )).unwrap();
+
+    pub static ref EXPIRED_BLOCKS_COUNTER: Counter = register_counter!(opts!(
+        "expired_blocks_total",
+        "Blocks deleted by the expiry sweeper."
+    )).unwrap();
+
+    // ---------------------------------------------------------------------------------------------
+    // compactor
+    // ---------------------------------------------------------------------------------------------
+    pub static ref COMPACTION_LOOP_TIME_GAUGE: Gauge = register_gauge!(opts!(
+        "compaction_loop_time",
+        "Compactor time for processing all candidate buckets (ms)."
+    )).unwrap();
+
+    pub static ref COMPACTION_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "compaction",
+        "Compactor progress",
+        &["item"]
+    ).unwrap();
+
+    pub static ref COMPACTION_MOVED_BYTES: Counter = register_counter!(opts!(
+        "compaction_moved_bytes_total",
+        "Live bytes rewritten by the compactor."
+    )).unwrap();
+
+    pub static ref COMPACTION_RECLAIMED_BYTES: Counter = register_counter!(opts!(
+        "compaction_reclaimed_bytes_total",
+        "Garbage bytes reclaimed by the compactor."
+    )).unwrap();
+
+    pub static ref COMPACTION_SEGMENTS: Counter = register_counter!(opts!(
+        "compaction_segments_total",
+        "Segments rewritten by the compactor."
+    )).unwrap();
+
     // ---------------------------------------------------------------------------------------------
     // validator
     // ---------------------------------------------------------------------------------------------
@@ -74,6 +128,12 @@ lazy_static! {
         "CRC checking time (mcs)."
     )).unwrap();
 
+    pub static ref CORRUPT_BLOCKS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "corrupt_blocks_total",
+        "Blocks quarantined by the validator.",
+        &["reason"]
+    ).unwrap();
+
     // ---------------------------------------------------------------------------------------------
     // hw
     // ---------------------------------------------------------------------------------------------
@@ -1,7 +1,10 @@
 use std::fs;
 use std::process;
+use std::sync::{Arc, Mutex};
 
+use crate::config::Durability;
 use crate::stora::meta::BucketMeta;
+use crate::stora::segment::SegmentWriter;
 
 #[derive(Debug, Clone)]
 pub struct Bucket {
@@ -13,6 +16,8 @@ pub struct Bucket {
     pub initial_size_bytes: u64,
     pub avail_size_bytes: u64,
     pub gc_size_bytes: u64,
+    pub move_size_bytes: u64,
+    pub writer: Arc<Mutex<SegmentWriter>>,
 }
 
 impl Bucket {
@@ -21,6 +26,8 @@ impl Bucket {
         volume_id: &String,
         bucket_path: &String,
         initial_size_bytes: u64,
+        segment_size_limit_bytes: u64,
+        durability: &Durability,
     ) -> Bucket {
         Bucket {
             id: id,
@@ -31,6 +38,12 @@ impl Bucket {
             initial_size_bytes: initial_size_bytes,
             avail_size_bytes: initial_size_bytes,
             gc_size_bytes: 0,
+            move_size_bytes: 0,
+            writer: Arc::new(Mutex::new(SegmentWriter::new(
+                bucket_path,
+                segment_size_limit_bytes,
+                durability.to_owned(),
+            ))),
         }
     }
 
@@ -38,6 +51,7 @@ impl Bucket {
         if !fs::metadata(&self.path).is_ok() {
             fs::create_dir_all(&self.path).expect("can't create bucket directory");
         }
+        self.writer.lock().unwrap().bootstrap()?;
         if let Ok(Some(bucket_meta)) = BucketMeta::get(BucketMeta::db_id(self.id, &self.volume_id))
         {
             self.cnt_blocks = bucket_meta.cnt_blocks;
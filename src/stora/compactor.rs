@@ -0,0 +1,287 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::time;
+
+use crate::metrics::{
+    COMPACTION_GAUGE, COMPACTION_LOOP_TIME_GAUGE, COMPACTION_MOVED_BYTES,
+    COMPACTION_RECLAIMED_BYTES, COMPACTION_SEGMENTS,
+};
+use crate::stora::disk::DISK;
+use crate::stora::meta::{BlockMeta, BucketMeta, Intent, RetiredSegment};
+use crate::stora::segment::{
+    erase_record, list_segments, read_record, scan_segment, segment_path, SegmentBuilder,
+};
+
+pub fn process(garbage_ratio: f64, grace_sec: u32, timeout: u32) {
+    tokio::spawn(async move {
+        info!("start compactor");
+        let grace = Duration::from_secs(grace_sec as u64);
+        let mut interval = time::interval(Duration::from_secs(timeout as u64));
+        interval.tick().await;
+        loop {
+            let now = Instant::now();
+            unlink_retired(grace);
+            let buckets = candidates(garbage_ratio);
+            COMPACTION_GAUGE
+                .with_label_values(&["pending_buckets"])
+                .set(buckets.len() as i64);
+            for (volume_id, bucket_id) in buckets {
+                match compact_bucket(&volume_id, bucket_id, grace) {
+                    Ok(0) => (),
+                    Ok(reclaimed) => info!(
+                        "compaction of bucket {}: {} bytes reclaimed",
+                        BucketMeta::db_id(bucket_id, &volume_id),
+                        reclaimed
+                    ),
+                    Err(e) => error!(
+                        "compaction of bucket {}: {}",
+                        BucketMeta::db_id(bucket_id, &volume_id),
+                        e
+                    ),
+                }
+                COMPACTION_GAUGE
+                    .with_label_values(&["pending_buckets"])
+                    .dec();
+            }
+            COMPACTION_LOOP_TIME_GAUGE.set(now.elapsed().as_millis() as f64);
+            interval.tick().await;
+        }
+    });
+}
+
+fn candidates(garbage_ratio: f64) -> Vec<(String, u32)> {
+    let disk = DISK.read().unwrap();
+    let mut res: Vec<(String, u32)> = vec![];
+    for v in &disk.volumes {
+        for b in v.buckets.iter() {
+            let used = b.initial_size_bytes.saturating_sub(b.avail_size_bytes);
+            if used > 0 && b.gc_size_bytes as f64 / used as f64 >= garbage_ratio {
+                res.push((v.id.to_owned(), b.id));
+            }
+        }
+    }
+    res
+}
+
+// rewritten segments are kept for a while for readers which fetched the old location
+fn unlink_retired(grace: Duration) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let retired = match RetiredSegment::fetch_all() {
+        Ok(retired) => retired,
+        Err(e) => {
+            error!("compactor: {}", e);
+            return;
+        }
+    };
+    for r in retired {
+        if r.ts + grace.as_secs() > now {
+            continue;
+        }
+        let bucket_path = match DISK.read().unwrap().bucket_path(&r.volume_id, r.bucket_id) {
+            Some(path) => path,
+            None => continue,
+        };
+        match fs::remove_file(segment_path(&bucket_path, r.segment_id)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => {
+                error!("can't unlink segment {}: {}", r.db_id(), e);
+                continue;
+            }
+        }
+        if let Err(_) = r.delete() {
+            error!("can't delete retired segment");
+        }
+    }
+}
+
+fn compact_bucket(volume_id: &String, bucket_id: u32, grace: Duration) -> Result<u64, String> {
+    let (bucket_path, writer) = {
+        let disk = DISK.read().unwrap();
+        match (
+            disk.bucket_path(volume_id, bucket_id),
+            disk.segment_writer(volume_id, bucket_id),
+        ) {
+            (Some(path), Some(writer)) => (path, writer),
+            _ => return Err("unknown bucket".to_string()),
+        }
+    };
+    let size_limit = writer.lock().unwrap().size_limit();
+    let retired: Vec<u32> = match RetiredSegment::fetch_all() {
+        Ok(retired) => retired
+            .into_iter()
+            .filter(|r| r.volume_id.eq(volume_id) && r.bucket_id == bucket_id)
+            .map(|r| r.segment_id)
+            .collect(),
+        Err(e) => return Err(e.to_string()),
+    };
+    // segments with records still being written are left alone until the write is committed
+    let pending: Vec<u32> = match Intent::fetch_all() {
+        Ok(intents) => intents
+            .into_iter()
+            .filter(|i| i.is_write_to(volume_id, bucket_id))
+            .map(|i| i.segment_id)
+            .collect(),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // a record is live while the block meta or its trashed version points to it
+    let mut sources: Vec<u32> = vec![];
+    let mut live: Vec<BlockMeta> = vec![];
+    let mut reclaimed: u64 = 0;
+    for segment_id in list_segments(&bucket_path) {
+        if retired.contains(&segment_id)
+            || pending.contains(&segment_id)
+            || !writer.lock().unwrap().is_cold(segment_id, grace)
+        {
+            continue;
+        }
+        let records = match scan_segment(&bucket_path, segment_id) {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("skip segment {} of {}: {}", segment_id, bucket_path, e);
+                continue;
+            }
+        };
+        let mut segment_live: Vec<BlockMeta> = vec![];
+        let mut dead_bytes: u64 = 0;
+        let mut erased_bytes: u64 = 0;
+        for (offset, header) in records {
+            if !header.is_deleted() {
+                let owners = vec![
+                    BlockMeta::get_stored(header.block_id.to_owned()),
+                    BlockMeta::get_trashed(header.block_id.to_owned()),
+                ];
+                let owner = owners
+                    .into_iter()
+                    .filter_map(|m| m.ok().and_then(|m| m))
+                    .find(|meta| {
+                        meta.volume_id.eq(volume_id)
+                            && meta.bucket_id == bucket_id
+                            && meta.segment_id == segment_id
+                            && meta.offset == offset
+                    });
+                if let Some(meta) = owner {
+                    segment_live.push(meta);
+                    continue;
+                }
+            }
+            if header.is_reclaimed() {
+                erased_bytes += header.size;
+            }
+            dead_bytes += header.size;
+        }
+        if dead_bytes == 0 {
+            continue;
+        }
+        sources.push(segment_id);
+        live.append(&mut segment_live);
+        // erased records left the bucket counters on erase
+        reclaimed += dead_bytes - erased_bytes;
+    }
+    if sources.is_empty() {
+        return Ok(0);
+    }
+
+    // copy live records into a fresh set of segments
+    let mut moves: Vec<(BlockMeta, u32, u64)> = vec![];
+    let mut builders: Vec<SegmentBuilder> = vec![];
+    let mut outputs: Vec<u32> = vec![];
+    let mut moved_bytes: u64 = 0;
+    let copy = (|| -> Result<(), String> {
+        for meta in live {
+            let payload = read_record(
+                &bucket_path,
+                meta.segment_id,
+                meta.offset,
+                &meta.id,
+                meta.size,
+            )?;
+            let full = match builders.last() {
+                Some(b) => b.size > 0 && b.size + payload.len() as u64 > size_limit,
+                None => true,
+            };
+            if full {
+                let segment_id = writer.lock().unwrap().allocate();
+                if let Err(_) = Intent::compact(volume_id, bucket_id, segment_id).store(true) {
+                    return Err("can't store compaction intent".to_string());
+                }
+                outputs.push(segment_id);
+                builders.push(SegmentBuilder::create(&bucket_path, segment_id)?);
+            }
+            let builder = builders.last_mut().unwrap();
+            let offset = builder.append(&meta.id, payload.as_slice())?;
+            moved_bytes += meta.size;
+            COMPACTION_MOVED_BYTES.inc_by(meta.size as f64);
+            DISK.write()
+                .unwrap()
+                .set_move_bytes(volume_id, bucket_id, moved_bytes);
+            moves.push((meta, builder.segment_id, offset));
+        }
+        Ok(())
+    })();
+    DISK.write()
+        .unwrap()
+        .set_move_bytes(volume_id, bucket_id, 0);
+    if let Err(e) = copy {
+        for b in builders {
+            b.abort();
+        }
+        rollback(volume_id, bucket_id, &bucket_path, &outputs);
+        return Err(e);
+    }
+    for b in builders {
+        if let Err(e) = b.finish() {
+            rollback(volume_id, bucket_id, &bucket_path, &outputs);
+            return Err(e);
+        }
+    }
+
+    if let Err(_) = BucketMeta::compact(volume_id, bucket_id, &moves, &outputs, &sources, reclaimed)
+    {
+        rollback(volume_id, bucket_id, &bucket_path, &outputs);
+        return Err("can't swap block locations".to_string());
+    }
+    // a block erased while it was copied leaves no meta behind, its copy is erased as well
+    for (meta, segment_id, offset) in moves.iter() {
+        let exists = match (
+            BlockMeta::get_stored(meta.id.to_owned()),
+            BlockMeta::get_trashed(meta.id.to_owned()),
+        ) {
+            (Ok(None), Ok(None)) => false,
+            _ => true,
+        };
+        if !exists {
+            if let Err(e) = erase_record(&bucket_path, *segment_id, *offset, &meta.id, false) {
+                error!("can't erase copy of block {}: {}", meta.id, e);
+            }
+        }
+    }
+    if let Err(_) = DISK
+        .write()
+        .unwrap()
+        .purge_object(volume_id, bucket_id, reclaimed)
+    {
+        error!("can't purge object");
+    }
+    COMPACTION_SEGMENTS.inc_by(sources.len() as f64);
+    COMPACTION_RECLAIMED_BYTES.inc_by(reclaimed as f64);
+    Ok(reclaimed)
+}
+
+/// Removes the output segments of an unfinished compaction, the blocks still point to the sources.
+pub fn rollback(volume_id: &String, bucket_id: u32, bucket_path: &String, outputs: &Vec<u32>) {
+    for segment_id in outputs.iter() {
+        let path = segment_path(bucket_path, *segment_id);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.tmp", path));
+        if let Err(_) = Intent::compact(volume_id, bucket_id, *segment_id).delete() {
+            error!("can't delete compaction intent");
+        }
+    }
+}
@@ -1,14 +1,26 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
 use std::path::Path;
-use std::sync::RwLock;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
 
-use uuid::Uuid;
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use lz4_compress::{compress, decompress};
 use vm_util::collections::HashMap;
 
-use crate::stora::meta::BlockMeta;
+use crate::config::Durability;
+use crate::stora::meta::{
+    BlockMeta, ContentHasher, CrcHasher, Intent, Precondition, RetiredSegment,
+};
+use crate::stora::segment::{
+    erase_record, mark_deleted, scan_segment, segment_path, RecordReader, RecordWriter,
+    SegmentWriter,
+};
 use crate::stora::volume::Volume;
 
+/// Part size for copying and streaming records
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
 lazy_static! {
     pub static ref DISK: RwLock<Disk> = RwLock::new(Disk::new());
 }
@@ -63,7 +75,7 @@ impl Disk {
                         return Ok(WriteSlot {
                             volume_id: v.id.clone(),
                             bucket_id: b.id.clone(),
-                            file_path: format!("{}/{}", b.path, Uuid::new_v4().to_simple()),
+                            writer: b.writer.clone(),
                         });
                     }
                 }
@@ -94,6 +106,51 @@ impl Disk {
         Ok(true)
     }
 
+    pub fn bucket_path(&self, volume_id: &String, bucket_id: u32) -> Option<String> {
+        let vi = self.volumes_mapping.get(volume_id)?.to_owned();
+        let v = self.volumes.get(vi)?;
+        let bi = v.buckets_mapping.get(&bucket_id)?.to_owned();
+        Some(v.buckets.get(bi)?.path.to_owned())
+    }
+
+    pub fn segment_writer(
+        &self,
+        volume_id: &String,
+        bucket_id: u32,
+    ) -> Option<Arc<Mutex<SegmentWriter>>> {
+        let vi = self.volumes_mapping.get(volume_id)?.to_owned();
+        let v = self.volumes.get(vi)?;
+        let bi = v.buckets_mapping.get(&bucket_id)?.to_owned();
+        Some(v.buckets.get(bi)?.writer.clone())
+    }
+
+    pub fn replace_object(
+        &mut self,
+        volume_id: &String,
+        bucket_id: u32,
+        deleted_bytes: u64,
+        written_bytes: u64,
+    ) -> Result<(), ()> {
+        let vi = self.volumes_mapping.get(volume_id).unwrap().to_owned();
+        let v = self.volumes.get_mut(vi).unwrap();
+
+        let bi = v.buckets_mapping.get(&bucket_id).unwrap().to_owned();
+        let b = v.buckets.get_mut(bi).unwrap();
+        b.gc_size_bytes += deleted_bytes;
+        b.avail_size_bytes -= written_bytes;
+
+        Ok(())
+    }
+
+    pub fn set_move_bytes(&mut self, volume_id: &String, bucket_id: u32, move_bytes: u64) {
+        let vi = self.volumes_mapping.get(volume_id).unwrap().to_owned();
+        let v = self.volumes.get_mut(vi).unwrap();
+
+        let bi = v.buckets_mapping.get(&bucket_id).unwrap().to_owned();
+        let b = v.buckets.get_mut(bi).unwrap();
+        b.move_size_bytes = move_bytes;
+    }
+
     pub fn delete_object(
         &mut self,
         volume_id: &String,
@@ -112,6 +169,24 @@ impl Disk {
         Ok(())
     }
 
+    pub fn restore_object(
+        &mut self,
+        volume_id: &String,
+        bucket_id: u32,
+        restored_bytes: u64,
+    ) -> Result<(), ()> {
+        let vi = self.volumes_mapping.get(volume_id).unwrap().to_owned();
+        let v = self.volumes.get_mut(vi).unwrap();
+        v.cnt_objects += 1;
+
+        let bi = v.buckets_mapping.get(&bucket_id).unwrap().to_owned();
+        let b = v.buckets.get_mut(bi).unwrap();
+        b.cnt_blocks += 1;
+        b.gc_size_bytes = b.gc_size_bytes.saturating_sub(restored_bytes);
+
+        Ok(())
+    }
+
     pub fn purge_object(
         &mut self,
         volume_id: &String,
@@ -123,7 +198,7 @@ impl Disk {
 
         let bi = v.buckets_mapping.get(&bucket_id).unwrap().to_owned();
         let b = v.buckets.get_mut(bi).unwrap();
-        b.gc_size_bytes -= deleted_bytes;
+        b.gc_size_bytes = b.gc_size_bytes.saturating_sub(deleted_bytes);
         b.avail_size_bytes += deleted_bytes;
 
         Ok(())
@@ -134,23 +209,21 @@ impl Disk {
 pub struct WriteSlot {
     pub volume_id: String,
     pub bucket_id: u32,
-    pub file_path: String,
+    pub writer: Arc<Mutex<SegmentWriter>>,
 }
 
 impl WriteSlot {
-    pub fn store(self, payload: Vec<u8>) -> Result<String, String> {
-        let path = Path::new(&self.file_path);
-        match File::create(&path) {
-            Err(why) => Err(why.to_string()),
-            Ok(file) => {
-                let mut file = file;
-                match file.write_all(payload.as_slice()) {
-                    Err(why) => Err(why.to_string()),
-                    Ok(_) => Ok(self.file_path),
-                }
-            }
-        }
+    /// Reserves a record in the active segment of the slot's bucket.
+    pub fn reserve(&self, block_id: &String, size: u64) -> Result<PendingRecord, String> {
+        PendingRecord::reserve(
+            &self.writer,
+            &self.volume_id,
+            self.bucket_id,
+            block_id,
+            size,
+        )
     }
+
     pub fn release(self, written_bytes: u64) {
         if let Err(_) = DISK
             .write()
@@ -161,41 +234,493 @@ impl WriteSlot {
         }
     }
 
-    pub fn commit(self, block_meta: BlockMeta) -> Result<(), ()> {
+    pub fn commit(self, block_meta: BlockMeta, precondition: &Precondition) -> Result<(), Error> {
         let written_bytes = block_meta.size;
-        match block_meta.store() {
-            Ok(_) => {
+        let intent = Intent::write(&block_meta);
+        match block_meta.store(precondition) {
+            Ok(replaced) => {
                 self.release(written_bytes);
+                // the replaced version is garbage now, the same as a deleted block
+                if let Some(prev) = replaced {
+                    if let Err(_) = DISK.write().unwrap().delete_object(
+                        &prev.volume_id,
+                        prev.bucket_id,
+                        prev.size,
+                    ) {
+                        error!("can't account replaced block {}", prev.id);
+                    }
+                }
+                Ok(())
             }
-            Err(_) => {
-                error!("can't store block meta");
+            Err(e) => {
+                if e.kind() != ErrorKind::PermissionDenied {
+                    error!("can't store block meta: {}", e);
+                }
+                rollback_write(&intent);
                 self.release(0);
+                Err(e)
             }
+        }
+    }
+}
+
+/// Record reserved in a segment and journaled by a write intent.
+/// The payload is written in parts, the record is flushed according to
+/// the durability mode on `finish`.
+pub struct PendingRecord {
+    record: RecordWriter,
+    intent: Intent,
+    crc: CrcHasher,
+    written: u64,
+}
+
+impl PendingRecord {
+    pub fn reserve(
+        writer: &Arc<Mutex<SegmentWriter>>,
+        volume_id: &String,
+        bucket_id: u32,
+        block_id: &String,
+        size: u64,
+    ) -> Result<PendingRecord, String> {
+        let (record, sync) = {
+            let mut writer = writer.lock().unwrap();
+            let record = writer.reserve(block_id, size)?;
+            (record, writer.durability() == &Durability::Fsync)
         };
+        let mut meta = BlockMeta::new();
+        meta.id = block_id.to_owned();
+        meta.volume_id = volume_id.to_owned();
+        meta.bucket_id = bucket_id;
+        meta.segment_id = record.segment_id;
+        meta.offset = record.offset;
+        meta.size = size;
+        let intent = Intent::write(&meta);
+        if let Err(_) = intent.store(sync) {
+            record.abort();
+            rollback_write(&intent);
+            return Err("can't store write intent".to_string());
+        }
+        Ok(PendingRecord {
+            record: record,
+            intent: intent,
+            crc: CrcHasher::new(),
+            written: 0,
+        })
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> u64 {
+        self.record.size() - self.written
+    }
+
+    /// Writes the next part of the payload, the record must be aborted on error
+    pub fn write(&mut self, part: &[u8]) -> Result<(), String> {
+        self.record.write_part(self.written, part)?;
+        self.crc.append(part);
+        self.written += part.len() as u64;
         Ok(())
     }
+
+    /// Returns the location and the crc of the written record
+    pub fn finish(self) -> Result<(u32, u64, String), String> {
+        if let Err(e) = self.record.complete(self.written) {
+            rollback_write(&self.intent);
+            return Err(e);
+        }
+        Ok((
+            self.record.segment_id,
+            self.record.offset,
+            self.crc.finish(),
+        ))
+    }
+
+    pub fn abort(self) {
+        self.record.abort();
+        rollback_write(&self.intent);
+    }
 }
 
-pub fn read_block(path: &String) -> Result<Vec<u8>, String> {
-    let path = Path::new(path);
-    match File::open(&path) {
-        Err(why) => Err(why.to_string()),
-        Ok(file) => {
-            let mut file = file;
-            let mut payload = Vec::new();
-            match file.read_to_end(&mut payload) {
-                Err(why) => Err(why.to_string()),
-                Ok(_) => Ok(payload),
+// the aborted record stays in its segment as garbage
+fn rollback_write(intent: &Intent) {
+    if let Err(_) = intent.rollback() {
+        error!("can't rollback intent {}", intent.db_id());
+    }
+    if let Err(_) = DISK.write().unwrap().replace_object(
+        &intent.volume_id,
+        intent.bucket_id,
+        intent.size,
+        intent.size,
+    ) {
+        error!("can't account aborted record");
+    }
+}
+
+// a record already written in full is flagged deleted, so it doesn't look live
+fn discard_record(meta: &BlockMeta) {
+    let bucket_path = match DISK
+        .read()
+        .unwrap()
+        .bucket_path(&meta.volume_id, meta.bucket_id)
+    {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = mark_deleted(&bucket_path, meta.segment_id, meta.offset, &meta.id) {
+        error!("can't discard record of block {}: {}", meta.id, e);
+    }
+}
+
+// collected payloads are checked before anything is written
+fn verify_payload(
+    hasher: Option<ContentHasher>,
+    payload: &[u8],
+    hash: &mut String,
+) -> Result<(), Error> {
+    match hasher {
+        Some(mut hasher) => {
+            hasher.append(payload);
+            hasher.verify(hash)
+        }
+        None => Ok(()),
+    }
+}
+
+fn size_mismatch() -> Error {
+    Error::new(ErrorKind::InvalidInput, "payload size mismatch")
+}
+
+// lz4 works with whole buffers, compressed payloads are collected before writing
+async fn collect_parts<S>(parts: &mut S, size: u64) -> Result<Vec<u8>, Error>
+where
+    S: Stream<Item = Result<Bytes, String>> + Unpin,
+{
+    let mut payload: Vec<u8> = Vec::with_capacity(size as usize);
+    while let Some(part) = parts.next().await {
+        let part = match part {
+            Ok(part) => part,
+            Err(e) => return Err(Error::new(ErrorKind::ConnectionAborted, e)),
+        };
+        if (payload.len() + part.len()) as u64 > size {
+            return Err(size_mismatch());
+        }
+        payload.extend_from_slice(&part);
+    }
+    if payload.len() as u64 != size {
+        return Err(size_mismatch());
+    }
+    Ok(payload)
+}
+
+async fn write_parts<S>(
+    mut record: PendingRecord,
+    parts: &mut S,
+    hasher: &mut Option<ContentHasher>,
+) -> Result<(u32, u64, String), Error>
+where
+    S: Stream<Item = Result<Bytes, String>> + Unpin,
+{
+    while let Some(part) = parts.next().await {
+        let part = match part {
+            Ok(part) => part,
+            Err(e) => {
+                record.abort();
+                return Err(Error::new(ErrorKind::ConnectionAborted, e));
             }
+        };
+        if part.len() as u64 > record.remaining() {
+            record.abort();
+            return Err(size_mismatch());
+        }
+        if let Err(e) = record.write(&part) {
+            record.abort();
+            return Err(Error::new(ErrorKind::Other, e));
+        }
+        if let Some(hasher) = hasher {
+            hasher.append(&part);
         }
     }
+    if record.remaining() > 0 {
+        record.abort();
+        return Err(size_mismatch());
+    }
+    record.finish().map_err(|e| Error::new(ErrorKind::Other, e))
+}
+
+fn write_payload(mut record: PendingRecord, payload: &[u8]) -> Result<(u32, u64, String), Error> {
+    if let Err(e) = record.write(payload) {
+        record.abort();
+        return Err(Error::new(ErrorKind::Other, e));
+    }
+    record.finish().map_err(|e| Error::new(ErrorKind::Other, e))
+}
+
+/// Writes a new block from the payload parts through a write slot.
+/// `meta.orig_size` is the declared payload size, `meta.compressed` asks for lz4.
+/// A compressed payload is collected in memory first, lz4 works with whole buffers.
+/// The precondition is checked before the payload is taken and again on commit.
+/// `meta.hash` is verified against the payload, or computed if empty.
+pub async fn write_block<S>(
+    mut meta: BlockMeta,
+    mut parts: S,
+    precondition: Precondition,
+) -> Result<BlockMeta, Error>
+where
+    S: Stream<Item = Result<Bytes, String>> + Unpin,
+{
+    if precondition != Precondition::Any {
+        match BlockMeta::get(meta.id.to_owned()) {
+            Ok(current) => precondition.check(current.as_ref())?,
+            Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string())),
+        }
+    }
+    let slot = { DISK.write().unwrap().get_write_slot() };
+    let slot = match slot {
+        Ok(slot) => slot,
+        Err(_) => {
+            error!("disk get slot");
+            return Err(Error::new(ErrorKind::Other, "no write slot"));
+        }
+    };
+    let mut hasher = ContentHasher::new(&meta.hash_fun);
+    let written = if meta.compressed {
+        let payload = match collect_parts(&mut parts, meta.orig_size).await {
+            Ok(payload) => verify_payload(hasher.take(), &payload, &mut meta.hash).map(|_| payload),
+            Err(e) => Err(e),
+        };
+        match payload {
+            Ok(payload) => {
+                let compressed_payload = compress(&payload);
+                let payload = if compressed_payload.len() < payload.len() {
+                    compressed_payload
+                } else {
+                    meta.compressed = false;
+                    payload
+                };
+                meta.size = payload.len() as u64;
+                match slot.reserve(&meta.id, meta.size) {
+                    Ok(record) => write_payload(record, payload.as_slice()),
+                    Err(e) => Err(Error::new(ErrorKind::Other, e)),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        meta.size = meta.orig_size;
+        match slot.reserve(&meta.id, meta.size) {
+            Ok(record) => write_parts(record, &mut parts, &mut hasher).await,
+            Err(e) => Err(Error::new(ErrorKind::Other, e)),
+        }
+    };
+    match written {
+        Ok((segment_id, offset, crc)) => {
+            meta.volume_id = slot.volume_id.to_owned();
+            meta.bucket_id = slot.bucket_id;
+            meta.segment_id = segment_id;
+            meta.offset = offset;
+            meta.crc = crc;
+            if let Some(hasher) = hasher {
+                if let Err(e) = hasher.verify(&mut meta.hash) {
+                    discard_record(&meta);
+                    rollback_write(&Intent::write(&meta));
+                    slot.release(0);
+                    return Err(e);
+                }
+            }
+            slot.commit(meta.clone(), &precondition)?;
+            Ok(meta)
+        }
+        Err(e) => {
+            slot.release(0);
+            Err(e)
+        }
+    }
+}
+
+/// Rewrites the block with the appended parts to a new record in the same bucket.
+/// Plain blocks are copied part by part, compressed blocks are rewritten as a whole.
+/// The commit fails if the block was changed meanwhile, so the precondition is checked once.
+/// The hash of the whole block is recomputed and verified against `hash` if one is given.
+pub async fn append_block<S>(
+    block_id: &String,
+    size: u64,
+    mut parts: S,
+    hash: String,
+    precondition: Precondition,
+) -> Result<BlockMeta, Error>
+where
+    S: Stream<Item = Result<Bytes, String>> + Unpin,
+{
+    let prev = match BlockMeta::get(block_id.to_owned()) {
+        Ok(Some(prev)) => prev,
+        _ => return Err(Error::new(ErrorKind::NotFound, "object not found")),
+    };
+    precondition.check(Some(&prev))?;
+    let writer = {
+        DISK.read()
+            .unwrap()
+            .segment_writer(&prev.volume_id, prev.bucket_id)
+    };
+    let writer = match writer {
+        Some(writer) => writer,
+        None => return Err(Error::new(ErrorKind::NotFound, "bucket not found")),
+    };
+    let reserve = |size: u64| {
+        PendingRecord::reserve(&writer, &prev.volume_id, prev.bucket_id, block_id, size)
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    };
+
+    let mut res = prev.clone();
+    res.hash = hash;
+    let mut hasher = ContentHasher::new(&prev.hash_fun);
+    let (segment_id, offset, crc) = if prev.compressed {
+        let payload = collect_parts(&mut parts, size).await?;
+        let mut body = match read_block(&prev) {
+            Ok(content) => match decompress(content.as_slice()) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("can't decompress block: {}", e);
+                    return Err(Error::new(ErrorKind::Other, "not written"));
+                }
+            },
+            Err(e) => {
+                error!("can't read block: {}", e);
+                return Err(Error::new(ErrorKind::Other, "not written"));
+            }
+        };
+        body.extend_from_slice(payload.as_slice());
+        verify_payload(hasher.take(), &body, &mut res.hash)?;
+        res.orig_size = body.len() as u64;
+        let compressed_body = compress(&body);
+        let body = if compressed_body.len() < body.len() {
+            compressed_body
+        } else {
+            res.compressed = false;
+            body
+        };
+        res.size = body.len() as u64;
+        write_payload(reserve(res.size)?, body.as_slice())?
+    } else {
+        res.orig_size = prev.orig_size + size;
+        res.size = prev.size + size;
+        let mut record = reserve(res.size)?;
+        let copied = match open_block(&prev) {
+            Ok(reader) => {
+                let mut pos: u64 = 0;
+                let mut res = Ok(());
+                while pos < reader.size {
+                    match reader.read(pos, CHUNK_SIZE).and_then(|part| {
+                        record.write(&part)?;
+                        if let Some(hasher) = hasher.as_mut() {
+                            hasher.append(&part);
+                        }
+                        Ok(part.len() as u64)
+                    }) {
+                        Ok(len) => pos += len,
+                        Err(e) => {
+                            res = Err(e);
+                            break;
+                        }
+                    }
+                }
+                res
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = copied {
+            error!("can't copy block: {}", e);
+            record.abort();
+            return Err(Error::new(ErrorKind::Other, "not written"));
+        }
+        write_parts(record, &mut parts, &mut hasher).await?
+    };
+    res.segment_id = segment_id;
+    res.offset = offset;
+    res.crc = crc;
+    if let Some(hasher) = hasher {
+        if let Err(e) = hasher.verify(&mut res.hash) {
+            discard_record(&res);
+            rollback_write(&Intent::write(&res));
+            return Err(e);
+        }
+    }
+
+    if let Err(e) = res.commit_append(&prev) {
+        rollback_write(&Intent::write(&res));
+        return Err(e);
+    }
+    Ok(res)
+}
+
+pub fn open_block(meta: &BlockMeta) -> Result<RecordReader, String> {
+    let bucket_path = match DISK
+        .read()
+        .unwrap()
+        .bucket_path(&meta.volume_id, meta.bucket_id)
+    {
+        Some(path) => path,
+        None => return Err(format!("unknown bucket {}", meta.bucket_id)),
+    };
+    RecordReader::open(
+        &bucket_path,
+        meta.segment_id,
+        meta.offset,
+        &meta.id,
+        meta.size,
+    )
+}
+
+pub type BlockParts = Pin<Box<dyn Stream<Item = Result<Vec<u8>, String>> + Send>>;
+
+/// Streams `length` bytes of the block from `offset` in parts.
+/// With `decompressed` the range applies to the original bytes of a compressed block,
+/// lz4 has no random access so the block is decompressed as a whole.
+pub fn stream_block(
+    meta: &BlockMeta,
+    offset: u64,
+    length: u64,
+    decompressed: bool,
+) -> Result<BlockParts, String> {
+    if decompressed {
+        let content = match decompress(read_block(meta)?.as_slice()) {
+            Ok(content) => content,
+            Err(e) => return Err(e.to_string()),
+        };
+        let end = std::cmp::min(offset + length, content.len() as u64) as usize;
+        let start = std::cmp::min(offset as usize, end);
+        let parts: Vec<Result<Vec<u8>, String>> = content[start..end]
+            .chunks(CHUNK_SIZE as usize)
+            .map(|part| Ok(part.to_vec()))
+            .collect();
+        return Ok(Box::pin(stream::iter(parts)));
+    }
+    let reader = open_block(meta)?;
+    let end = std::cmp::min(offset + length, reader.size);
+    Ok(Box::pin(stream::unfold(
+        (reader, offset),
+        move |(reader, pos)| async move {
+            if pos >= end {
+                return None;
+            }
+            match reader.read(pos, std::cmp::min(CHUNK_SIZE, end - pos)) {
+                Ok(part) => {
+                    let next = pos + part.len() as u64;
+                    Some((Ok(part), (reader, next)))
+                }
+                Err(e) => Some((Err(e), (reader, end))),
+            }
+        },
+    )))
+}
+
+pub fn read_block(meta: &BlockMeta) -> Result<Vec<u8>, String> {
+    open_block(meta)?.read(0, meta.size)
 }
 
 pub fn mark_block_as_deleted(meta: BlockMeta) -> Result<(), ()> {
     let volume_id = meta.volume_id.to_owned();
     let bucket_id = meta.bucket_id.to_owned();
     let object_size = meta.size.to_owned();
-    if let Err(_) = meta.delete() {
+    if let Err(_) = meta.delete(true) {
         error!("can't mark block as deleted");
         return Err(());
     }
@@ -210,29 +735,182 @@ pub fn mark_block_as_deleted(meta: BlockMeta) -> Result<(), ()> {
     Ok(())
 }
 
-pub fn purge_block(meta: BlockMeta) -> Result<(), ()> {
-    let volume_id = meta.volume_id.to_owned();
-    let bucket_id = meta.bucket_id.to_owned();
-    let object_size = meta.size.to_owned();
-    match std::fs::remove_file(&meta.path) {
-        Ok(_) => {
-            if let Err(_) = meta.purge() {
-                error!("can't mark block as deleted");
-                return Err(());
-            }
-            if let Err(_) = DISK
-                .write()
+/// Marks all blocks of the object as deleted at once, returns their ids.
+pub fn mark_object_as_deleted(object_id: &String) -> Result<Vec<String>, ()> {
+    match BlockMeta::delete_object(object_id) {
+        Ok(deleted) => release_deleted(deleted),
+        Err(_) => {
+            error!("can't mark blocks of object {} as deleted", object_id);
+            Err(())
+        }
+    }
+}
+
+/// Marks the found blocks as deleted at once, returns their ids.
+pub fn mark_blocks_as_deleted(block_ids: &Vec<String>) -> Result<Vec<String>, ()> {
+    match BlockMeta::delete_many(block_ids) {
+        Ok(deleted) => release_deleted(deleted),
+        Err(_) => {
+            error!("can't mark blocks as deleted");
+            Err(())
+        }
+    }
+}
+
+/// Moves the blocks expired at `now` to the delete queue, returns their ids.
+pub fn sweep_expired_blocks(now: u64, limit: u32) -> Result<Vec<String>, ()> {
+    match BlockMeta::expire_due(now, limit) {
+        Ok(expired) => release_deleted(expired),
+        Err(_) => {
+            error!("can't delete expired blocks");
+            Err(())
+        }
+    }
+}
+
+fn release_deleted(deleted: Vec<BlockMeta>) -> Result<Vec<String>, ()> {
+    let mut disk = DISK.write().unwrap();
+    for meta in deleted.iter() {
+        if let Err(_) = disk.delete_object(&meta.volume_id, meta.bucket_id, meta.size) {
+            error!("can't delete object");
+            return Err(());
+        }
+    }
+    Ok(deleted.into_iter().map(|meta| meta.id).collect())
+}
+
+/// Deletes the block bypassing the trash and erases its bytes and the ones of its
+/// trashed version before returning, including the copies left by the compactor in
+/// retired segments. The erased bytes are freed in the bucket counters at once.
+/// Versions replaced by writes are left to GC.
+/// Returns false when there is neither a block nor a trashed one.
+pub fn erase_block(block_id: &String) -> Result<bool, String> {
+    let mut erased: Vec<BlockMeta> = vec![];
+    if let Ok(Some(meta)) = BlockMeta::get_stored(block_id.to_owned()) {
+        if let Err(_) = meta.clone().delete(false) {
+            return Err("can't delete block".to_string());
+        }
+        if let Err(_) =
+            DISK.write()
                 .unwrap()
-                .purge_object(&volume_id, bucket_id, object_size)
-            {
-                error!("can't purge object");
-                return Err(());
+                .delete_object(&meta.volume_id, meta.bucket_id, meta.size)
+        {
+            return Err("can't delete object".to_string());
+        }
+        purge_block(meta.clone(), true)?;
+        erased.push(meta);
+    }
+    match BlockMeta::drop_trashed(block_id) {
+        Ok(Some(trashed)) => {
+            purge_block(trashed.clone(), true)?;
+            erased.push(trashed);
+        }
+        Ok(None) => (),
+        Err(_) => return Err("can't drop trashed block".to_string()),
+    }
+    for meta in erased.iter() {
+        erase_retired_copies(meta)?;
+    }
+    Ok(!erased.is_empty())
+}
+
+// a retired segment stays on disk for the compaction grace period, every record
+// of the block found there is an old copy of it
+fn erase_retired_copies(meta: &BlockMeta) -> Result<(), String> {
+    let bucket_path = match DISK
+        .read()
+        .unwrap()
+        .bucket_path(&meta.volume_id, meta.bucket_id)
+    {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let retired = match RetiredSegment::fetch_all() {
+        Ok(retired) => retired,
+        Err(e) => return Err(format!("can't fetch retired segments: {}", e)),
+    };
+    for r in retired {
+        if !r.volume_id.eq(&meta.volume_id) || r.bucket_id != meta.bucket_id {
+            continue;
+        }
+        let records = match scan_segment(&bucket_path, r.segment_id) {
+            Ok(records) => records,
+            // unlinked meanwhile
+            Err(_) if !Path::new(&segment_path(&bucket_path, r.segment_id)).exists() => continue,
+            Err(e) => return Err(format!("can't scan retired segment {}: {}", r.db_id(), e)),
+        };
+        for (offset, header) in records {
+            if header.block_id.eq(&meta.id) {
+                erase_record(&bucket_path, r.segment_id, offset, &meta.id, false)
+                    .map_err(|e| format!("can't erase copy in {}: {}", r.db_id(), e))?;
             }
-            Ok(())
         }
-        Err(e) => {
-            error!("can't delete file: {}", e);
-            Err(())
+    }
+    Ok(())
+}
+
+/// Restores a trashed block, see `BlockMeta::undelete` for the errors.
+pub fn undelete_block(block_id: &String) -> Result<BlockMeta, Error> {
+    let meta = BlockMeta::undelete(block_id)?;
+    if let Err(_) = DISK
+        .write()
+        .unwrap()
+        .restore_object(&meta.volume_id, meta.bucket_id, meta.size)
+    {
+        error!("can't restore object");
+    }
+    Ok(meta)
+}
+
+// the record stays in its segment as garbage until the segment is rewritten,
+// `erase` zero-fills its payload and frees its bytes in the bucket counters right away
+pub fn purge_block(meta: BlockMeta, erase: bool) -> Result<(), String> {
+    let bucket_path = match DISK
+        .read()
+        .unwrap()
+        .bucket_path(&meta.volume_id, meta.bucket_id)
+    {
+        Some(path) => path,
+        None => {
+            return Err(format!(
+                "unknown bucket {} for block {}",
+                meta.bucket_id, meta.id
+            ));
+        }
+    };
+    // the segment may be rewritten by the compactor already
+    let live = match BlockMeta::get_stored(meta.id.to_owned()) {
+        Ok(Some(current)) => current.gc_key() == meta.gc_key(),
+        _ => false,
+    };
+    let mut erased = false;
+    if !live {
+        let purged = match erase {
+            true => erase_record(&bucket_path, meta.segment_id, meta.offset, &meta.id, true),
+            false => mark_deleted(&bucket_path, meta.segment_id, meta.offset, &meta.id),
+        };
+        match purged {
+            Ok(_) => erased = erase,
+            // the segment is gone, there is nothing left to purge
+            Err(_) if !Path::new(&segment_path(&bucket_path, meta.segment_id)).exists() => (),
+            Err(e) => return Err(format!("can't delete record: {}", e)),
         }
     }
+    if !erased {
+        if let Err(_) = meta.purge() {
+            return Err("can't mark block as deleted".to_string());
+        }
+        return Ok(());
+    }
+    if let Err(_) = meta.clone().purge_erased() {
+        return Err("can't mark block as erased".to_string());
+    }
+    if let Err(_) = DISK
+        .write()
+        .unwrap()
+        .purge_object(&meta.volume_id, meta.bucket_id, meta.size)
+    {
+        return Err("can't purge object".to_string());
+    }
+    Ok(())
 }
@@ -0,0 +1,33 @@
+use std::time::{Duration, SystemTime};
+
+use tokio::{task, time};
+
+use crate::metrics::EXPIRED_BLOCKS_COUNTER;
+use crate::stora::disk::sweep_expired_blocks;
+
+/// Deletes expired blocks, up to `batch` blocks per tick.
+pub fn process(batch: u32, timeout: u32) {
+    tokio::spawn(async move {
+        info!("start expiry sweeper");
+        let mut interval = time::interval(Duration::from_secs(timeout as u64));
+        loop {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            match sweep_expired_blocks(now, batch) {
+                Ok(block_ids) => {
+                    EXPIRED_BLOCKS_COUNTER.inc_by(block_ids.len() as f64);
+                    // a full batch means there may be more expired blocks right away,
+                    // other tasks still get the worker between the batches
+                    if batch > 0 && block_ids.len() == batch as usize {
+                        task::yield_now().await;
+                        continue;
+                    }
+                }
+                Err(_) => error!("expiry: can't sweep expired blocks"),
+            }
+            interval.tick().await;
+        }
+    });
+}
@@ -1,27 +1,133 @@
-use crate::metrics::GC_LOOP_TIME_GAUGE;
+use crate::config::Storage;
+use crate::metrics::{
+    GC_DEAD_LETTER_COUNTER, GC_FAILED_COUNTER, GC_IDLE_COUNTER, GC_LOOP_TIME_GAUGE,
+    GC_PURGED_COUNTER,
+};
 use crate::stora::disk::purge_block;
-use crate::stora::meta::BlockMeta;
-use std::time::{Duration, Instant};
+use crate::stora::meta::{BlockMeta, GcRetry};
+use crate::stora::status::CpuStatus;
+use crate::stora::throttle::Throttle;
+use chrono::{Local, NaiveTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::time;
 
-pub fn process(batch: u32, timeout: u32) {
+const IOWAIT_BACKOFF_MAX_SEC: u64 = 60;
+
+pub fn process(config: &Storage) {
+    let config = config.clone();
+    let windows = config.gc_windows().unwrap_or_default();
     tokio::spawn(async move {
         info!("start GC");
-        let mut interval = time::interval(Duration::from_secs(timeout as u64));
+        let batch = config.gc_batch;
+        let timeout = Duration::from_secs(config.gc_timeout_sec as u64);
+        let mut interval = time::interval(timeout);
+        let mut deletes = Throttle::new(config.gc_deletes_per_sec as u64);
+        let mut bytes = Throttle::new(config.gc_bytes_per_sec);
+        let mut backoff = timeout;
         loop {
+            if !in_window(&windows, Local::now().time()) {
+                GC_IDLE_COUNTER.inc();
+                interval.tick().await;
+                continue;
+            }
+            // foreground IO goes first, the pause doubles while iowait stays high
+            if iowait_exceeded(config.gc_iowait_threshold) {
+                backoff = std::cmp::min(backoff * 2, Duration::from_secs(IOWAIT_BACKOFF_MAX_SEC));
+                info!("gc: iowait is high, back off for {:?}", backoff);
+                time::delay_for(backoff).await;
+                continue;
+            }
+            backoff = timeout;
             let now = Instant::now();
+            let ts = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            if let Err(_) = BlockMeta::expire_trash(
+                ts.saturating_sub(config.delete_retention_sec as u64),
+                batch,
+            ) {
+                error!("gc: can't expire trash");
+            }
+            let mut items: Vec<(BlockMeta, u32)> = vec![];
             match BlockMeta::fetch_deleted(batch) {
-                Ok(items) => {
-                    for bm in items {
-                        let _ = purge_block(bm);
-                    }
-                }
+                Ok(deleted) => items.extend(deleted.into_iter().map(|bm| (bm, 0))),
                 Err(e) => {
                     error!("gc: {}", e);
                 }
             }
+            match GcRetry::fetch_due(ts, batch) {
+                Ok(due) => items.extend(due.into_iter().map(|r| (r.meta, r.attempts))),
+                Err(e) => {
+                    error!("gc retry: {}", e);
+                }
+            }
+            for (bm, attempts) in items {
+                if iowait_exceeded(config.gc_iowait_threshold) {
+                    break;
+                }
+                deletes.acquire(&bm.volume_id, 1).await;
+                bytes.acquire(&bm.volume_id, bm.size).await;
+                purge(
+                    bm,
+                    attempts,
+                    ts,
+                    config.gc_retry_limit,
+                    config.gc_retry_backoff_sec,
+                );
+            }
             GC_LOOP_TIME_GAUGE.set(now.elapsed().as_millis() as f64);
             interval.tick().await;
         }
     });
 }
+
+fn in_window(windows: &Vec<(NaiveTime, NaiveTime)>, now: NaiveTime) -> bool {
+    windows.is_empty()
+        || windows.iter().any(|(from, to)| match from <= to {
+            true => *from <= now && now < *to,
+            false => *from <= now || now < *to,
+        })
+}
+
+fn iowait_exceeded(threshold: f32) -> bool {
+    threshold > 0.0 && CpuStatus::get().iowait > threshold
+}
+
+// a failed purge is retried with exponential backoff until the retry limit
+fn purge(bm: BlockMeta, attempts: u32, ts: u64, retry_limit: u32, retry_backoff_sec: u32) {
+    let e = match purge_block(bm.clone(), false) {
+        Ok(_) => {
+            GC_PURGED_COUNTER.inc();
+            return;
+        }
+        Err(e) => e,
+    };
+    GC_FAILED_COUNTER.inc();
+    let attempts = attempts + 1;
+    let backoff = (retry_backoff_sec as u64).saturating_mul(1 << std::cmp::min(attempts - 1, 16));
+    let retry = GcRetry {
+        meta: bm,
+        attempts: attempts,
+        next_try: ts + backoff,
+        error: e,
+    };
+    if attempts >= retry_limit {
+        error!(
+            "gc: give up block {} after {} attempts: {}",
+            retry.meta.id, attempts, retry.error
+        );
+        GC_DEAD_LETTER_COUNTER.inc();
+        if let Err(_) = retry.dead_letter() {
+            error!("gc: can't move block to dead letter queue");
+        }
+    } else {
+        warn!(
+            "gc: purge block {} failed (attempt {}): {}",
+            retry.meta.id, attempts, retry.error
+        );
+        if let Err(_) = retry.store() {
+            error!("gc: can't store purge retry");
+        }
+    }
+}
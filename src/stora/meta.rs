@@ -1,15 +1,18 @@
 extern crate walkdir;
 
+use std::collections::HashMap;
 use std::fmt::Error;
-use std::fs::OpenOptions;
 use std::io::ErrorKind;
-use std::io::prelude::*;
 use std::sync::RwLock;
 use std::time::SystemTime;
 
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
 use highway::{HighwayBuilder, HighwayHash, Key};
 use rmps::Serializer;
-use rocksdb::{DB, IteratorMode, WriteBatch};
+use rocksdb::{Direction, IteratorMode, WriteBatch, WriteOptions, DB};
 use serde::{Deserialize, Serialize};
 use tokio::time;
 use walkdir::WalkDir;
@@ -18,7 +21,10 @@ use crate::api::rpc::block_api;
 use crate::binutil::setup;
 use crate::config::Config;
 use crate::metrics::META_DB_SIZE_GAUGE;
-use crate::stora::disk::read_block;
+use crate::stora::disk::DISK;
+
+/// Entries scanned for one page of `BlockMeta::list`
+const LIST_SCAN_LIMIT: usize = 10000;
 
 #[derive(Debug)]
 pub struct Metainfo {}
@@ -64,6 +70,33 @@ pub fn db_size() -> Option<u64> {
     DBSIZE.read().unwrap().to_owned()
 }
 
+/// Position of a background pass saved under its name, kept across restarts.
+pub fn load_checkpoint(name: &str) -> Option<String> {
+    match METADB.read().unwrap().as_ref() {
+        Some(db) => {
+            let cf = db.cf_handle("checkpoints").unwrap();
+            match db.get_cf(cf, name) {
+                Ok(Some(v)) => Some(String::from_utf8_lossy(&v).to_string()),
+                _ => None,
+            }
+        }
+        None => None,
+    }
+}
+
+pub fn store_checkpoint(name: &str, position: &String) -> Result<(), ()> {
+    match METADB.write().unwrap().as_ref() {
+        Some(db) => {
+            let cf = db.cf_handle("checkpoints").unwrap();
+            match db.put_cf(cf, name, position.as_str()) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(()),
+            }
+        }
+        None => Err(()),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum HashFun {
     Other,
@@ -74,6 +107,18 @@ pub enum HashFun {
     Hgw256,
 }
 
+impl HashFun {
+    /// Hash function of a write without a client hint: the server computes Hgw128
+    /// when no hash is sent, a hash of unknown function is stored as sent.
+    pub fn of_write(hint: Option<HashFun>, hash: &String) -> HashFun {
+        match hint {
+            Some(hash_fun) => hash_fun,
+            None if hash.is_empty() => HashFun::Hgw128,
+            None => HashFun::Other,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum Compression {
     None,
@@ -93,9 +138,18 @@ pub struct BlockMeta {
     pub size: u64,
     pub orig_size: u64,
     pub compressed: bool,
-    pub path: String,
+    pub segment_id: u32,
+    pub offset: u64,
     pub created: u64,
     pub last_check_ts: u64,
+    #[serde(default)]
+    pub user_meta: HashMap<String, String>,
+    /// deletion time while the block waits in the trash
+    #[serde(default)]
+    pub deleted: u64,
+    /// expiration time, 0 never expires
+    #[serde(default)]
+    pub expires_at: u64,
 }
 
 impl BlockMeta {
@@ -116,9 +170,13 @@ impl BlockMeta {
             size: 0,
             orig_size: 0,
             compressed: false,
-            path: "".to_string(),
+            segment_id: 0,
+            offset: 0,
             created: now,
             last_check_ts: now,
+            user_meta: HashMap::new(),
+            deleted: 0,
+            expires_at: 0,
         }
     }
 
@@ -133,8 +191,26 @@ impl BlockMeta {
 
     #[inline]
     pub fn decode(payload: Vec<u8>) -> Result<BlockMeta, Error> {
-        let r: BlockMeta = rmps::from_read_ref(&payload).unwrap();
-        Ok(r)
+        rmps::from_read_ref(&payload).map_err(|_| Error)
+    }
+
+    /// Key of the stored record in `delete_queue`: a block id may have several
+    /// records waiting for purging (previous versions), so the location is used.
+    #[inline]
+    pub fn gc_key(&self) -> String {
+        format!(
+            "{}:{:08}:{:016x}",
+            BucketMeta::db_id(self.bucket_id, &self.volume_id),
+            self.segment_id,
+            self.offset
+        )
+    }
+
+    /// Key in `object_index`: object id and block id split by a zero byte,
+    /// so the blocks of an object are a contiguous range ordered by block id.
+    #[inline]
+    pub fn object_index_key(&self) -> String {
+        format!("{}\u{0}{}", self.object_id, self.id)
     }
 
     pub fn to_grpc(&self) -> block_api::Meta {
@@ -153,42 +229,96 @@ impl BlockMeta {
             },
             last_check: self.last_check_ts,
             size: self.size,
+            user_meta: self.user_meta.to_owned(),
+            expires_at: self.expires_at,
         }
     }
 
-    pub fn store(self) -> Result<(), ()> {
+    /// Expired blocks are gone for readers even before the sweeper deletes them.
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.expires_at != 0
+            && self.expires_at
+                <= SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+    }
+
+    /// Key of the block in `expiry_index`, which orders expiring blocks by time.
+    #[inline]
+    fn expiry_key(&self) -> String {
+        format!("{:016x}:{}", self.expires_at, self.id)
+    }
+
+    /// Commits the written block if the precondition holds for the current entry.
+    /// The check and the commit batch run under the metadb write lock,
+    /// so a concurrent write of the same block can't slip in between.
+    /// A replaced version goes to the delete queue in the same batch and is returned.
+    pub fn store(self, precondition: &Precondition) -> Result<Option<BlockMeta>, std::io::Error> {
         match METADB.write().unwrap().as_ref() {
             Some(db) => {
                 let blocks_cf = db.cf_handle("blocks").unwrap();
-                let buckets_cf = db.cf_handle("buckets").unwrap();
 
-                let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
-                let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
-                    Ok(None) => return Err(()),
-                    Ok(r) => match BucketMeta::decode(r.unwrap()) {
-                        Ok(res) => res,
-                        Err(e) => {
-                            error!("decode bucket meta: {}", e);
-                            return Err(());
-                        }
+                let prev = match db.get_cf(blocks_cf, self.id.as_str()) {
+                    Ok(Some(r)) => match BlockMeta::decode(r) {
+                        Ok(prev) => Some(prev),
+                        Err(e) => return Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
                     },
-                    _ => return Err(()),
+                    Ok(None) => None,
+                    Err(e) => return Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
                 };
+                precondition.check(prev.as_ref().filter(|prev| !prev.is_expired()))?;
+
+                let bucket_error = || std::io::Error::new(ErrorKind::NotFound, "bucket not found");
+                let mut batch = WriteBatch::default();
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
+                // the previous version is deleted first, the new entries below override its keys
+                if let Some(prev) = prev.as_ref() {
+                    prev.delete_in(db, &mut batch, &mut buckets, false)
+                        .map_err(|_| bucket_error())?;
+                }
+                let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
+                let bucket = BucketMeta::load_in(db, &mut buckets, &bucket_db_id)
+                    .map_err(|_| bucket_error())?;
                 bucket.cnt_blocks += 1;
                 bucket.avail_size_bytes -= self.size;
 
-                let mut batch = WriteBatch::default();
+                let intents_cf = db.cf_handle("intents").unwrap();
+                let object_index_cf = db.cf_handle("object_index").unwrap();
+                let _ = batch.delete_cf(intents_cf, self.gc_key());
+                if !self.object_id.is_empty() {
+                    let _ =
+                        batch.put_cf(object_index_cf, self.object_index_key(), self.id.as_str());
+                }
+                if self.expires_at != 0 {
+                    let expiry_index_cf = db.cf_handle("expiry_index").unwrap();
+                    let _ = batch.put_cf(expiry_index_cf, self.expiry_key(), self.id.as_str());
+                }
                 let _ = batch.put_cf(
                     blocks_cf,
                     &self.id.as_str().to_owned(),
                     self.encode().unwrap(),
                 );
-                let _ = batch.put_cf(
-                    buckets_cf,
-                    bucket_db_id.to_owned(),
-                    bucket.encode().unwrap(),
-                );
+                BucketMeta::put_all_in(db, &mut batch, buckets);
+
+                match db.write(batch) {
+                    Ok(_) => Ok(prev),
+                    Err(e) => Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
+                }
+            }
+            None => Err(std::io::Error::new(ErrorKind::Other, "metadb is not ready")),
+        }
+    }
 
+    /// Drops the purged record from `delete_queue` and `gc_retry`.
+    pub fn purge(self) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+                let mut batch = WriteBatch::default();
+                let _ = batch.delete_cf(delete_queue_cf, self.gc_key());
+                GcRetry::delete_in(db, &mut batch, &self.gc_key())?;
                 match db.write(batch) {
                     Ok(_) => Ok(()),
                     Err(_) => Err(()),
@@ -198,35 +328,37 @@ impl BlockMeta {
         }
     }
 
-    pub fn purge(self) -> Result<(), ()> {
+    /// Drops the erased block from the delete queue and frees its bytes in the bucket.
+    pub fn purge_erased(self) -> Result<(), ()> {
         match METADB.write().unwrap().as_ref() {
             Some(db) => {
-                let buckets_cf = db.cf_handle("buckets").unwrap();
                 let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
-
+                let mut batch = WriteBatch::default();
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
                 let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
-                let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
-                    Ok(None) => return Err(()),
-                    Ok(r) => match BucketMeta::decode(r.unwrap()) {
-                        Ok(res) => res,
-                        Err(e) => {
-                            error!("decode bucket meta: {}", e);
-                            return Err(());
-                        }
-                    },
-                    _ => return Err(()),
-                };
-                bucket.gc_size_bytes -= self.size;
+                let bucket = BucketMeta::load_in(db, &mut buckets, &bucket_db_id)?;
+                bucket.gc_size_bytes = bucket.gc_size_bytes.saturating_sub(self.size);
                 bucket.avail_size_bytes += self.size;
+                let _ = batch.delete_cf(delete_queue_cf, self.gc_key());
+                GcRetry::delete_in(db, &mut batch, &self.gc_key())?;
+                BucketMeta::put_all_in(db, &mut batch, buckets);
+                match db.write(batch) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
 
+    /// Without `trash` the block goes straight to the delete queue.
+    pub fn delete(self, trash: bool) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
                 let mut batch = WriteBatch::default();
-                let _ = batch.delete_cf(delete_queue_cf, &self.id.as_str().to_owned());
-                let _ = batch.put_cf(
-                    buckets_cf,
-                    bucket_db_id.to_owned(),
-                    bucket.encode().unwrap(),
-                );
-
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
+                self.delete_in(db, &mut batch, &mut buckets, trash)?;
+                BucketMeta::put_all_in(db, &mut batch, buckets);
                 match db.write(batch) {
                     Ok(_) => Ok(()),
                     Err(_) => Err(()),
@@ -236,43 +368,333 @@ impl BlockMeta {
         }
     }
 
-    pub fn delete(self) -> Result<(), ()> {
+    /// Moves the found blocks to the trash in one batch, unknown ids are skipped.
+    /// Returns the deleted blocks.
+    pub fn delete_many(block_ids: &Vec<String>) -> Result<Vec<BlockMeta>, ()> {
         match METADB.write().unwrap().as_ref() {
             Some(db) => {
                 let blocks_cf = db.cf_handle("blocks").unwrap();
-                let buckets_cf = db.cf_handle("buckets").unwrap();
+                let mut deleted: Vec<BlockMeta> = vec![];
+                let mut batch = WriteBatch::default();
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
+                for block_id in block_ids {
+                    if deleted.iter().any(|meta| meta.id.eq(block_id)) {
+                        continue;
+                    }
+                    let meta = match db.get_cf(blocks_cf, block_id.as_str()) {
+                        Ok(Some(r)) => match BlockMeta::decode(r) {
+                            Ok(meta) => meta,
+                            Err(_) => return Err(()),
+                        },
+                        Ok(None) => continue,
+                        Err(_) => return Err(()),
+                    };
+                    meta.delete_in(db, &mut batch, &mut buckets, true)?;
+                    deleted.push(meta);
+                }
+                BucketMeta::put_all_in(db, &mut batch, buckets);
+                match db.write(batch) {
+                    Ok(_) => Ok(deleted),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    /// Moves every block of the object to the trash in one batch.
+    /// Returns the deleted blocks.
+    pub fn delete_object(object_id: &String) -> Result<Vec<BlockMeta>, ()> {
+        if object_id.is_empty() {
+            return Ok(vec![]);
+        }
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let object_index_cf = db.cf_handle("object_index").unwrap();
+                let prefix = format!("{}\u{0}", object_id);
+                let block_ids = db
+                    .iterator_cf(
+                        object_index_cf,
+                        IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+                    )
+                    .unwrap()
+                    .take_while(|(k, _)| k.starts_with(prefix.as_bytes()))
+                    .map(|(_, v)| String::from_utf8_lossy(&v).to_string())
+                    .collect::<Vec<_>>();
+
+                let mut deleted: Vec<BlockMeta> = vec![];
+                let mut batch = WriteBatch::default();
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
+                for block_id in block_ids {
+                    let meta = match db.get_cf(blocks_cf, block_id.as_str()) {
+                        Ok(Some(r)) => match BlockMeta::decode(r) {
+                            Ok(meta) => meta,
+                            Err(_) => return Err(()),
+                        },
+                        Ok(None) => continue,
+                        Err(_) => return Err(()),
+                    };
+                    if !meta.object_id.eq(object_id) {
+                        continue;
+                    }
+                    meta.delete_in(db, &mut batch, &mut buckets, true)?;
+                    deleted.push(meta);
+                }
+                BucketMeta::put_all_in(db, &mut batch, buckets);
+                match db.write(batch) {
+                    Ok(_) => Ok(deleted),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    // adds the deletion to the batch, bucket counters are collected in `buckets`.
+    // A trashed block can be restored until GC expires it, the trash keeps only the
+    // latest deleted version of a block id and the older one goes to the delete queue.
+    fn delete_in(
+        &self,
+        db: &DB,
+        batch: &mut WriteBatch,
+        buckets: &mut HashMap<String, BucketMeta>,
+        trash: bool,
+    ) -> Result<(), ()> {
+        let blocks_cf = db.cf_handle("blocks").unwrap();
+        let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+        let object_index_cf = db.cf_handle("object_index").unwrap();
+
+        let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
+        let bucket = BucketMeta::load_in(db, buckets, &bucket_db_id)?;
+        bucket.cnt_blocks -= 1;
+        bucket.gc_size_bytes += self.size;
+
+        let _ = batch.delete_cf(blocks_cf, &self.id.as_str().to_owned());
+        let _ = batch.delete_cf(object_index_cf, self.object_index_key());
+        if self.expires_at != 0 {
+            let expiry_index_cf = db.cf_handle("expiry_index").unwrap();
+            let _ = batch.delete_cf(expiry_index_cf, self.expiry_key());
+        }
+        if !trash {
+            let _ = batch.put_cf(
+                delete_queue_cf,
+                self.gc_key(),
+                self.clone().encode().unwrap(),
+            );
+            return Ok(());
+        }
+        let trash_cf = db.cf_handle("trash").unwrap();
+        let trash_index_cf = db.cf_handle("trash_index").unwrap();
+        match db.get_cf(trash_cf, self.id.as_str()) {
+            Ok(Some(r)) => match BlockMeta::decode(r) {
+                Ok(older) => {
+                    let _ = batch.delete_cf(trash_index_cf, older.trash_key());
+                    let _ = batch.put_cf(
+                        delete_queue_cf,
+                        older.gc_key(),
+                        older.clone().encode().unwrap(),
+                    );
+                }
+                Err(_) => return Err(()),
+            },
+            Ok(None) => (),
+            Err(_) => return Err(()),
+        }
+        let mut trashed = self.clone();
+        trashed.deleted = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let _ = batch.put_cf(trash_index_cf, trashed.trash_key(), trashed.id.as_str());
+        let _ = batch.put_cf(trash_cf, self.id.as_str(), trashed.encode().unwrap());
+        Ok(())
+    }
+
+    /// Key of the block in `trash_index`, which orders the trash by deletion time.
+    #[inline]
+    fn trash_key(&self) -> String {
+        format!("{:016x}:{}", self.deleted, self.id)
+    }
+
+    /// Brings the trashed block back with its bucket counters.
+    /// Fails with NotFound when it isn't in the trash and AlreadyExists when
+    /// the block id has been written again.
+    pub fn undelete(block_id: &String) -> Result<BlockMeta, std::io::Error> {
+        let db_issue = || std::io::Error::new(std::io::ErrorKind::Other, "metadb issue");
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let object_index_cf = db.cf_handle("object_index").unwrap();
+                let trash_cf = db.cf_handle("trash").unwrap();
+                let trash_index_cf = db.cf_handle("trash_index").unwrap();
+                let trashed = match db.get_cf(trash_cf, block_id.as_str()) {
+                    Ok(Some(r)) => BlockMeta::decode(r).map_err(|_| db_issue())?,
+                    Ok(None) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            "block is not in the trash",
+                        ))
+                    }
+                    Err(_) => return Err(db_issue()),
+                };
+                match db.get_cf(blocks_cf, block_id.as_str()) {
+                    Ok(None) => (),
+                    Ok(Some(_)) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::AlreadyExists,
+                            "block exists",
+                        ))
+                    }
+                    Err(_) => return Err(db_issue()),
+                }
+
+                let mut batch = WriteBatch::default();
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
+                let bucket_db_id = BucketMeta::db_id(trashed.bucket_id, &trashed.volume_id);
+                let bucket =
+                    BucketMeta::load_in(db, &mut buckets, &bucket_db_id).map_err(|_| db_issue())?;
+                bucket.cnt_blocks += 1;
+                // compaction may have reclaimed garbage of the bucket since the delete
+                bucket.gc_size_bytes = bucket.gc_size_bytes.saturating_sub(trashed.size);
+
+                let _ = batch.delete_cf(trash_index_cf, trashed.trash_key());
+                let _ = batch.delete_cf(trash_cf, block_id.as_str());
+                let mut meta = trashed.clone();
+                meta.deleted = 0;
+                if !meta.object_id.is_empty() {
+                    let _ =
+                        batch.put_cf(object_index_cf, meta.object_index_key(), meta.id.as_str());
+                }
+                if meta.expires_at != 0 {
+                    let expiry_index_cf = db.cf_handle("expiry_index").unwrap();
+                    let _ = batch.put_cf(expiry_index_cf, meta.expiry_key(), meta.id.as_str());
+                }
+                let _ = batch.put_cf(blocks_cf, meta.id.as_str(), meta.clone().encode().unwrap());
+                BucketMeta::put_all_in(db, &mut batch, buckets);
+                match db.write(batch) {
+                    Ok(_) => Ok(meta),
+                    Err(_) => Err(db_issue()),
+                }
+            }
+            None => Err(db_issue()),
+        }
+    }
+
+    /// Moves up to `limit` blocks deleted before `deleted_before` from the trash
+    /// to the delete queue. Returns the number of moved blocks.
+    pub fn expire_trash(deleted_before: u64, limit: u32) -> Result<usize, ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
                 let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+                let trash_cf = db.cf_handle("trash").unwrap();
+                let trash_index_cf = db.cf_handle("trash_index").unwrap();
+                let bound = format!("{:016x}:", deleted_before);
+                let expired = db
+                    .iterator_cf(trash_index_cf, IteratorMode::Start)
+                    .unwrap()
+                    .take_while(|(k, _)| k.as_ref() < bound.as_bytes())
+                    .take(limit as usize)
+                    .collect::<Vec<_>>();
+                let mut batch = WriteBatch::default();
+                for (k, v) in expired.iter() {
+                    let _ = batch.delete_cf(trash_index_cf, k.as_ref());
+                    match db.get_cf(trash_cf, v.as_ref()) {
+                        Ok(Some(r)) => match BlockMeta::decode(r) {
+                            Ok(meta) => {
+                                let _ = batch.delete_cf(trash_cf, meta.id.as_str());
+                                let _ = batch.put_cf(
+                                    delete_queue_cf,
+                                    meta.gc_key(),
+                                    meta.encode().unwrap(),
+                                );
+                            }
+                            Err(_) => return Err(()),
+                        },
+                        Ok(None) => (),
+                        Err(_) => return Err(()),
+                    }
+                }
+                match db.write(batch) {
+                    Ok(_) => Ok(expired.len()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
 
-                let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
-                let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
-                    Ok(None) => return Err(()),
-                    Ok(r) => match BucketMeta::decode(r.unwrap()) {
-                        Ok(res) => res,
-                        Err(e) => {
-                            error!("decode bucket meta: {}", e);
-                            return Err(());
-                        }
+    /// Moves up to `limit` blocks expired at `now` to the delete queue in one batch,
+    /// they skip the trash. Returns the deleted blocks.
+    pub fn expire_due(now: u64, limit: u32) -> Result<Vec<BlockMeta>, ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let expiry_index_cf = db.cf_handle("expiry_index").unwrap();
+                let bound = format!("{:016x}:", now + 1);
+                let due = db
+                    .iterator_cf(expiry_index_cf, IteratorMode::Start)
+                    .unwrap()
+                    .take_while(|(k, _)| k.as_ref() < bound.as_bytes())
+                    .take(limit as usize)
+                    .collect::<Vec<_>>();
+                let mut expired: Vec<BlockMeta> = vec![];
+                let mut batch = WriteBatch::default();
+                let mut buckets: HashMap<String, BucketMeta> = HashMap::new();
+                for (k, v) in due.iter() {
+                    let _ = batch.delete_cf(expiry_index_cf, k.as_ref());
+                    let meta = match db.get_cf(blocks_cf, v.as_ref()) {
+                        Ok(Some(r)) => match BlockMeta::decode(r) {
+                            Ok(meta) => meta,
+                            Err(_) => return Err(()),
+                        },
+                        Ok(None) => continue,
+                        Err(_) => return Err(()),
+                    };
+                    // the key may be left from a version which was rewritten since
+                    if meta.expiry_key().as_bytes() != k.as_ref()
+                        || expired.iter().any(|e| e.id.eq(&meta.id))
+                    {
+                        continue;
+                    }
+                    meta.delete_in(db, &mut batch, &mut buckets, false)?;
+                    expired.push(meta);
+                }
+                BucketMeta::put_all_in(db, &mut batch, buckets);
+                match db.write(batch) {
+                    Ok(_) => Ok(expired),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    /// Moves the trashed version of the block to the delete queue and returns it.
+    pub fn drop_trashed(block_id: &String) -> Result<Option<BlockMeta>, ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+                let trash_cf = db.cf_handle("trash").unwrap();
+                let trash_index_cf = db.cf_handle("trash_index").unwrap();
+                let trashed = match db.get_cf(trash_cf, block_id.as_str()) {
+                    Ok(Some(r)) => match BlockMeta::decode(r) {
+                        Ok(meta) => meta,
+                        Err(_) => return Err(()),
                     },
-                    _ => return Err(()),
+                    Ok(None) => return Ok(None),
+                    Err(_) => return Err(()),
                 };
-                bucket.cnt_blocks -= 1;
-                bucket.gc_size_bytes += self.size;
-
                 let mut batch = WriteBatch::default();
-                let _ = batch.delete_cf(blocks_cf, &self.id.as_str().to_owned());
-                let _ = batch.put_cf(
-                    buckets_cf,
-                    bucket_db_id.to_owned(),
-                    bucket.encode().unwrap(),
-                );
+                let _ = batch.delete_cf(trash_index_cf, trashed.trash_key());
+                let _ = batch.delete_cf(trash_cf, block_id.as_str());
                 let _ = batch.put_cf(
                     delete_queue_cf,
-                    &self.id.as_str().to_owned(),
-                    self.encode().unwrap(),
+                    trashed.gc_key(),
+                    trashed.clone().encode().unwrap(),
                 );
-
                 match db.write(batch) {
-                    Ok(_) => Ok(()),
+                    Ok(_) => Ok(Some(trashed)),
                     Err(_) => Err(()),
                 }
             }
@@ -280,6 +702,20 @@ impl BlockMeta {
         }
     }
 
+    /// The trashed version of the block, None if there is none.
+    pub fn get_trashed(block_id: String) -> Result<Option<BlockMeta>, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("trash").unwrap();
+                match db.get_cf(cf, block_id.as_str()) {
+                    Ok(Some(r)) => BlockMeta::decode(r).map(Some),
+                    _ => Ok(None),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
     pub fn fetch_deleted(limit: u32) -> Result<Vec<BlockMeta>, Error> {
         let mut res: Vec<BlockMeta> = vec![];
         match METADB.read().unwrap().as_ref() {
@@ -309,7 +745,13 @@ impl BlockMeta {
         Ok(res)
     }
 
+    /// The block as readers see it, an expired block is not found.
     pub fn get(block_id: String) -> Result<Option<BlockMeta>, Error> {
+        BlockMeta::get_stored(block_id).map(|meta| meta.filter(|meta| !meta.is_expired()))
+    }
+
+    /// The stored entry of the block, expired or not.
+    pub fn get_stored(block_id: String) -> Result<Option<BlockMeta>, Error> {
         match METADB.read().unwrap().as_ref() {
             Some(db) => {
                 let cf = db.cf_handle("blocks").unwrap();
@@ -329,143 +771,1008 @@ impl BlockMeta {
         }
     }
 
-    pub fn append(block_id: String, payload: Vec<u8>) -> Result<Option<BlockMeta>, std::io::Error> {
-        match METADB.write().unwrap().as_ref() {
+    /// Page of blocks after the cursor (a block id, empty from the start) in block id order.
+    /// Returns the cursor of the next page, None when the column family is exhausted.
+    /// At most `LIST_SCAN_LIMIT` entries are scanned per page, so a selective filter
+    /// may return a short or empty page with a cursor.
+    pub fn list(
+        filter: &BlockFilter,
+        cursor: &String,
+        limit: usize,
+    ) -> Result<(Vec<BlockMeta>, Option<String>), Error> {
+        let mut res: Vec<BlockMeta> = vec![];
+        match METADB.read().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("blocks").unwrap();
-                match db.get_cf(cf, block_id.as_str()) {
-                    Ok(None) => {
-                        Err(std::io::Error::new(ErrorKind::NotFound, "object not found"))
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let mode = match cursor.is_empty() {
+                    true => IteratorMode::Start,
+                    false => IteratorMode::From(cursor.as_bytes(), Direction::Forward),
+                };
+                let iterator = db.iterator_cf(blocks_cf, mode).unwrap();
+                let mut scanned: usize = 0;
+                let mut last = cursor.to_owned();
+                for (k, v) in iterator {
+                    if k.as_ref() == cursor.as_bytes() {
+                        continue;
                     }
-                    Ok(r) => match BlockMeta::decode(r.unwrap()) {
-                        Ok(mut res) => {
-                            match OpenOptions::new().append(true).open(&res.path) {
-                                Err(why) => {
-                                    Err(why)
-                                }
-                                Ok(file) => {
-                                    let mut file = file;
-                                    match file.write_all(payload.as_slice()) {
-                                        Err(why) => {
-                                            Err(why)
-                                        }
-                                        Ok(_) => {
-                                            let body = match read_block(&res.path) {
-                                                Ok(content) => {
-                                                    content
-                                                }
-                                                Err(e) => {
-                                                    error!("can't read block: {}", e);
-                                                    return Err(std::io::Error::new(ErrorKind::Interrupted, "not written"));
-                                                }
-                                            };
-                                            let new_crc = BlockMeta::crc(body.clone());
-
-                                            res.size = body.len() as u64;
-                                            res.crc = new_crc;
-
-                                            let blocks_cf = db.cf_handle("blocks").unwrap();
-                                            let buckets_cf = db.cf_handle("buckets").unwrap();
-
-                                            let bucket_db_id = BucketMeta::db_id(res.bucket_id, &res.volume_id);
-                                            let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
-                                                Ok(None) => return Err(std::io::Error::new(ErrorKind::NotFound, "bucket not found")),
-                                                Ok(r) => match BucketMeta::decode(r.unwrap()) {
-                                                    Ok(res) => res,
-                                                    Err(_e) => {
-                                                        return Err(std::io::Error::new(ErrorKind::InvalidData, "bucket not found"));
-                                                    }
-                                                },
-                                                _ => {
-                                                    return Err(std::io::Error::new(ErrorKind::NotFound, "bucket not found"));
-                                                }
-                                            };
-                                            bucket.avail_size_bytes -= payload.len() as u64;
-
-                                            let mut batch = WriteBatch::default();
-                                            let res_meta = res.clone();
-                                            let _ = batch.put_cf(
-                                                blocks_cf,
-                                                &res.id.as_str().to_owned(),
-                                                res.encode().unwrap(),
-                                            );
-                                            let _ = batch.put_cf(
-                                                buckets_cf,
-                                                bucket_db_id.to_owned(),
-                                                bucket.encode().unwrap(),
-                                            );
-
-                                            match db.write(batch) {
-                                                Ok(_) => Ok(Some(res_meta)),
-                                                Err(_e) => Err(std::io::Error::new(ErrorKind::ConnectionAborted, "meta db can't be lock")),
-                                            }
-                                        }
-                                    }
-                                }
+                    if res.len() >= limit || scanned >= LIST_SCAN_LIMIT {
+                        return Ok((res, Some(last)));
+                    }
+                    scanned += 1;
+                    last = String::from_utf8_lossy(&k).to_string();
+                    match BlockMeta::decode(v.to_vec()) {
+                        Ok(bm) => {
+                            if filter.matches(&bm) && !bm.is_expired() {
+                                res.push(bm);
                             }
                         }
-                        Err(_e) => {
-                            Err(std::io::Error::new(ErrorKind::InvalidData, "meta decoding issue"))
+                        Err(e) => {
+                            error!("decode block meta: {}", e);
+                            return Err(e);
                         }
-                    },
-                    _ => {
-                        Err(std::io::Error::new(ErrorKind::NotFound, "object not found"))
                     }
                 }
+                Ok((res, None))
             }
-            None => {
-                Err(std::io::Error::new(ErrorKind::ConnectionAborted, "meta db can't be lock"))
-            }
+            None => Ok((res, None)),
         }
     }
 
-    pub fn exists(block_id: String) -> Result<bool, Error> {
+    /// Next `limit` blocks after the cursor for background passes. Unlike `list`
+    /// entries which can't be decoded are skipped, so a pass never gets stuck.
+    /// Returns the cursor to resume from, None at the end of the blocks.
+    pub fn scan(cursor: &String, limit: usize) -> (Vec<BlockMeta>, Option<String>) {
+        let mut res: Vec<BlockMeta> = vec![];
         match METADB.read().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("blocks").unwrap();
-                match db.get_cf(cf, block_id.as_str()) {
-                    Ok(None) => Ok(false),
-                    Ok(_) => Ok(true),
-                    _ => Ok(false),
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let mode = match cursor.is_empty() {
+                    true => IteratorMode::Start,
+                    false => IteratorMode::From(cursor.as_bytes(), Direction::Forward),
+                };
+                let mut scanned: usize = 0;
+                let mut last = cursor.to_owned();
+                for (k, v) in db.iterator_cf(blocks_cf, mode).unwrap() {
+                    if k.as_ref() == cursor.as_bytes() {
+                        continue;
+                    }
+                    if scanned >= limit {
+                        return (res, Some(last));
+                    }
+                    scanned += 1;
+                    last = String::from_utf8_lossy(&k).to_string();
+                    match BlockMeta::decode(v.to_vec()) {
+                        Ok(bm) => res.push(bm),
+                        Err(e) => error!("decode block meta {}: {}", last, e),
+                    }
                 }
+                (res, None)
             }
-            None => Ok(false),
+            None => (res, None),
         }
     }
 
-    pub fn crc(payload: Vec<u8>) -> String {
-        let key = Key([
-            0x0706050403020100,
-            0x0F0E0D0C0B0A0908,
-            0x1716151413121110,
-            0x1F1E1D1C1B1A1918,
-        ]);
-        let mut hasher = HighwayBuilder::new(&key);
-        hasher.append(payload.as_slice());
-        let res: [u64; 2] = hasher.finalize128();
-        format!("{:x}{:x}", res[0], res[1]).to_string()
+    /// Blocks of the object in block id order, looked up through `object_index`.
+    pub fn fetch_by_object(object_id: &String) -> Result<Vec<BlockMeta>, Error> {
+        let mut res: Vec<BlockMeta> = vec![];
+        if object_id.is_empty() {
+            return Ok(res);
+        }
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let object_index_cf = db.cf_handle("object_index").unwrap();
+                let prefix = format!("{}\u{0}", object_id);
+                let iterator = db
+                    .iterator_cf(
+                        object_index_cf,
+                        IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+                    )
+                    .unwrap();
+                for (k, v) in iterator {
+                    if !k.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+                    let block_id = String::from_utf8_lossy(&v).to_string();
+                    match db.get_cf(blocks_cf, block_id.as_str()) {
+                        Ok(Some(r)) => match BlockMeta::decode(r) {
+                            Ok(bm) => {
+                                if bm.object_id.eq(object_id) && !bm.is_expired() {
+                                    res.push(bm);
+                                }
+                            }
+                            Err(e) => {
+                                error!("decode block meta: {}", e);
+                                return Err(e);
+                            }
+                        },
+                        _ => (),
+                    }
+                }
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+
+    /// Merges user metadata entries, an empty value removes the key.
+    pub fn merge_user_meta(&mut self, entries: HashMap<String, String>, replace: bool) {
+        if replace {
+            self.user_meta.clear();
+        }
+        for (key, value) in entries {
+            if value.is_empty() {
+                self.user_meta.remove(&key);
+            } else {
+                self.user_meta.insert(key, value);
+            }
+        }
+    }
+
+    /// Rewrites the `blocks` entry only, the payload and the counters are untouched.
+    /// Fails if the block was deleted or rewritten since it was read.
+    pub fn update(&self) -> Result<(), std::io::Error> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let object_index_cf = db.cf_handle("object_index").unwrap();
+                let current = match db.get_cf(blocks_cf, self.id.as_str()) {
+                    Ok(Some(r)) => match BlockMeta::decode(r) {
+                        Ok(current) => current,
+                        Err(e) => return Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
+                    },
+                    Ok(None) => {
+                        return Err(std::io::Error::new(ErrorKind::NotFound, "block not found"))
+                    }
+                    Err(e) => return Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
+                };
+                if current.gc_key() != self.gc_key() {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Interrupted,
+                        "block was changed",
+                    ));
+                }
+
+                let mut batch = WriteBatch::default();
+                if !current.object_id.eq(&self.object_id) {
+                    let _ = batch.delete_cf(object_index_cf, current.object_index_key());
+                    if !self.object_id.is_empty() {
+                        let _ = batch.put_cf(
+                            object_index_cf,
+                            self.object_index_key(),
+                            self.id.as_str(),
+                        );
+                    }
+                }
+                let _ = batch.put_cf(blocks_cf, self.id.as_str(), self.clone().encode().unwrap());
+                match db.write(batch) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
+                }
+            }
+            None => Err(std::io::Error::new(ErrorKind::Other, "metadb is not ready")),
+        }
+    }
+
+    /// Looks up several blocks under one metadb lock, results follow the ids order.
+    /// rocksdb 0.13 has no multi_get for column families, so the lookups are still
+    /// sequential, only the lock and the cf handle are shared.
+    pub fn get_many(block_ids: &Vec<String>) -> Result<Vec<Option<BlockMeta>>, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("blocks").unwrap();
+                let mut res: Vec<Option<BlockMeta>> = Vec::with_capacity(block_ids.len());
+                for block_id in block_ids {
+                    match db.get_cf(cf, block_id.as_str()) {
+                        Ok(Some(r)) => match BlockMeta::decode(r) {
+                            Ok(meta) if meta.is_expired() => res.push(None),
+                            Ok(meta) => res.push(Some(meta)),
+                            Err(e) => {
+                                error!("decode block meta: {}", e);
+                                return Err(e);
+                            }
+                        },
+                        _ => res.push(None),
+                    }
+                }
+                Ok(res)
+            }
+            None => Ok(block_ids.iter().map(|_| None).collect()),
+        }
+    }
+
+    /// Commits the block rewritten by append, the previous version goes to the delete queue.
+    /// Fails if the block was changed since `prev` was read.
+    pub fn commit_append(&self, prev: &BlockMeta) -> Result<(), std::io::Error> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let buckets_cf = db.cf_handle("buckets").unwrap();
+                let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+                let intents_cf = db.cf_handle("intents").unwrap();
+
+                match db.get_cf(blocks_cf, self.id.as_str()) {
+                    Ok(Some(r)) => match BlockMeta::decode(r) {
+                        Ok(current) => {
+                            if current.gc_key() != prev.gc_key() {
+                                return Err(std::io::Error::new(
+                                    ErrorKind::Interrupted,
+                                    "block was changed",
+                                ));
+                            }
+                        }
+                        Err(_e) => {
+                            return Err(std::io::Error::new(
+                                ErrorKind::Other,
+                                "meta decoding issue",
+                            ));
+                        }
+                    },
+                    _ => {
+                        return Err(std::io::Error::new(ErrorKind::NotFound, "object not found"));
+                    }
+                };
+
+                let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
+                let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
+                    Ok(Some(r)) => match BucketMeta::decode(r) {
+                        Ok(res) => res,
+                        Err(_e) => {
+                            return Err(std::io::Error::new(ErrorKind::Other, "bucket not found"));
+                        }
+                    },
+                    _ => {
+                        return Err(std::io::Error::new(ErrorKind::NotFound, "bucket not found"));
+                    }
+                };
+                bucket.avail_size_bytes -= self.size;
+                bucket.gc_size_bytes += prev.size;
+
+                let mut batch = WriteBatch::default();
+                let _ = batch.delete_cf(intents_cf, self.gc_key());
+                let _ = batch.put_cf(
+                    blocks_cf,
+                    &self.id.as_str().to_owned(),
+                    self.clone().encode().unwrap(),
+                );
+                let _ = batch.put_cf(
+                    buckets_cf,
+                    bucket_db_id.to_owned(),
+                    bucket.encode().unwrap(),
+                );
+                let _ = batch.put_cf(
+                    delete_queue_cf,
+                    prev.gc_key(),
+                    prev.clone().encode().unwrap(),
+                );
+
+                match db.write(batch) {
+                    Ok(_) => {
+                        let _ = DISK.write().unwrap().replace_object(
+                            &self.volume_id,
+                            self.bucket_id,
+                            prev.size,
+                            self.size,
+                        );
+                        Ok(())
+                    }
+                    Err(_e) => Err(std::io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "meta db can't be lock",
+                    )),
+                }
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "meta db can't be lock",
+            )),
+        }
+    }
+
+    /// Updates the check timestamp if the block still points to the checked record.
+    pub fn checked(self, ts: u64) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("blocks").unwrap();
+                let mut current = match db.get_cf(cf, self.id.as_str()) {
+                    Ok(Some(r)) => match BlockMeta::decode(r) {
+                        Ok(res) => res,
+                        Err(_) => return Err(()),
+                    },
+                    Ok(None) => return Ok(()),
+                    Err(_) => return Err(()),
+                };
+                if current.segment_id != self.segment_id || current.offset != self.offset {
+                    return Ok(());
+                }
+                current.last_check_ts = ts;
+                match db.put_cf(cf, &self.id.as_str().to_owned(), current.encode().unwrap()) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    pub fn exists(block_id: String) -> Result<bool, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("blocks").unwrap();
+                match db.get_cf(cf, block_id.as_str()) {
+                    Ok(Some(r)) => match BlockMeta::decode(r) {
+                        Ok(meta) => Ok(!meta.is_expired()),
+                        Err(e) => Err(e),
+                    },
+                    _ => Ok(false),
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn crc(payload: Vec<u8>) -> String {
+        let mut hasher = CrcHasher::new();
+        hasher.append(payload.as_slice());
+        hasher.finish()
+    }
+}
+
+/// Block meta written before blocks were stored as segment records,
+/// the payload of such a block is a file at `path`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LegacyBlockMeta {
+    pub id: String,
+    pub object_id: String,
+    pub volume_id: String,
+    pub bucket_id: u32,
+    pub content_type: String,
+    pub hash_fun: HashFun,
+    pub hash: String,
+    pub crc: String,
+    pub size: u64,
+    pub orig_size: u64,
+    pub compressed: bool,
+    pub path: String,
+    pub created: u64,
+    pub last_check_ts: u64,
+}
+
+impl LegacyBlockMeta {
+    #[inline]
+    pub fn decode(payload: Vec<u8>) -> Result<LegacyBlockMeta, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
+    }
+
+    /// Meta of the block moved to the record at the given location
+    pub fn into_block_meta(self, segment_id: u32, offset: u64) -> BlockMeta {
+        let mut meta = BlockMeta::new();
+        meta.id = self.id;
+        meta.object_id = self.object_id;
+        meta.volume_id = self.volume_id;
+        meta.bucket_id = self.bucket_id;
+        meta.content_type = self.content_type;
+        meta.hash_fun = self.hash_fun;
+        meta.hash = self.hash;
+        meta.crc = self.crc;
+        meta.size = self.size;
+        meta.orig_size = self.orig_size;
+        meta.compressed = self.compressed;
+        meta.segment_id = segment_id;
+        meta.offset = offset;
+        meta.created = self.created;
+        meta.last_check_ts = self.last_check_ts;
+        meta
+    }
+
+    /// Legacy entries of `blocks` or `delete_queue`, the current entries are skipped.
+    pub fn fetch_all(cf_name: &str) -> Result<Vec<LegacyBlockMeta>, Error> {
+        let mut res: Vec<LegacyBlockMeta> = vec![];
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle(cf_name).unwrap();
+                let iterator = db.iterator_cf(cf, IteratorMode::Start).unwrap();
+                for (_k, v) in iterator {
+                    if BlockMeta::decode(v.to_vec()).is_ok() {
+                        continue;
+                    }
+                    match LegacyBlockMeta::decode(v.to_vec()) {
+                        Ok(r) => res.push(r),
+                        Err(e) => {
+                            error!("decode legacy block meta: {}", e);
+                            return Err(e);
+                        }
+                    }
+                }
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+
+    /// Points the legacy blocks to the records they were copied to and clears
+    /// the intent of the segment holding them in one batch.
+    pub fn migrate(
+        volume_id: &String,
+        bucket_id: u32,
+        segment_id: u32,
+        moved: &Vec<BlockMeta>,
+    ) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let object_index_cf = db.cf_handle("object_index").unwrap();
+                let intents_cf = db.cf_handle("intents").unwrap();
+
+                let mut batch = WriteBatch::default();
+                for meta in moved.iter() {
+                    if !meta.object_id.is_empty() {
+                        let _ = batch.put_cf(
+                            object_index_cf,
+                            meta.object_index_key(),
+                            meta.id.as_str(),
+                        );
+                    }
+                    let _ = batch.put_cf(
+                        blocks_cf,
+                        &meta.id.as_str().to_owned(),
+                        meta.clone().encode().unwrap(),
+                    );
+                }
+                let intent = Intent::compact(volume_id, bucket_id, segment_id);
+                let _ = batch.delete_cf(intents_cf, intent.db_id());
+
+                match db.write(batch) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    /// Drops a legacy entry of `delete_queue` whose file is already removed.
+    pub fn purge(self) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let buckets_cf = db.cf_handle("buckets").unwrap();
+                let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+
+                let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
+                let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
+                    Ok(Some(r)) => match BucketMeta::decode(r) {
+                        Ok(res) => res,
+                        Err(e) => {
+                            error!("decode bucket meta: {}", e);
+                            return Err(());
+                        }
+                    },
+                    _ => return Err(()),
+                };
+                bucket.gc_size_bytes = bucket.gc_size_bytes.saturating_sub(self.size);
+                bucket.avail_size_bytes += self.size;
+
+                let mut batch = WriteBatch::default();
+                let _ = batch.delete_cf(delete_queue_cf, &self.id.as_str().to_owned());
+                let _ = batch.put_cf(
+                    buckets_cf,
+                    bucket_db_id.to_owned(),
+                    bucket.encode().unwrap(),
+                );
+
+                match db.write(batch) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+}
+
+/// Condition on the current block for conditional writes.
+/// A failed check is reported as `ErrorKind::PermissionDenied`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Precondition {
+    Any,
+    /// the block must not exist
+    Absent,
+    /// the block must exist with this crc
+    Crc(String),
+}
+
+impl Precondition {
+    pub fn check(&self, current: Option<&BlockMeta>) -> Result<(), std::io::Error> {
+        let ok = match (self, current) {
+            (Precondition::Any, _) => true,
+            (Precondition::Absent, current) => current.is_none(),
+            (Precondition::Crc(crc), Some(current)) => crc.eq(&current.crc),
+            (Precondition::Crc(_), None) => false,
+        };
+        match ok {
+            true => Ok(()),
+            false => Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "precondition failed",
+            )),
+        }
+    }
+}
+
+/// Optional conditions of a blocks listing, unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct BlockFilter {
+    pub volume_id: Option<String>,
+    pub bucket_ids: Vec<u32>,
+    pub content_type: Option<String>,
+    pub created_from: Option<u64>,
+    pub created_to: Option<u64>,
+    pub size_min: Option<u64>,
+    pub size_max: Option<u64>,
+}
+
+impl BlockFilter {
+    pub fn matches(&self, meta: &BlockMeta) -> bool {
+        self.volume_id
+            .as_ref()
+            .map_or(true, |v| v.eq(&meta.volume_id))
+            && (self.bucket_ids.is_empty() || self.bucket_ids.contains(&meta.bucket_id))
+            && self
+                .content_type
+                .as_ref()
+                .map_or(true, |c| c.eq(&meta.content_type))
+            && self.created_from.map_or(true, |ts| meta.created >= ts)
+            && self.created_to.map_or(true, |ts| meta.created <= ts)
+            && self.size_min.map_or(true, |size| meta.orig_size >= size)
+            && self.size_max.map_or(true, |size| meta.orig_size <= size)
+    }
+}
+
+/// Incremental form of `BlockMeta::crc` for payloads written in parts
+pub struct CrcHasher {
+    hasher: HighwayBuilder,
+}
+
+const CRC_KEY: Key = Key([
+    0x0706050403020100,
+    0x0F0E0D0C0B0A0908,
+    0x1716151413121110,
+    0x1F1E1D1C1B1A1918,
+]);
+
+impl CrcHasher {
+    pub fn new() -> CrcHasher {
+        CrcHasher {
+            hasher: HighwayBuilder::new(&CRC_KEY),
+        }
+    }
+
+    #[inline]
+    pub fn append(&mut self, data: &[u8]) {
+        self.hasher.append(data);
+    }
+
+    pub fn finish(self) -> String {
+        let res: [u64; 2] = self.hasher.finalize128();
+        format!("{:x}{:x}", res[0], res[1]).to_string()
+    }
+}
+
+const CONTENT_KEY: Key = Key([0, 0, 0, 0]);
+
+/// Digest of the original payload by the block `HashFun`, hex encoded.
+/// Sha128 is SHA-1, highway hashes are unkeyed (all-zero key) so clients can
+/// compute them too, every u64 word is printed as 16 hex digits.
+pub struct ContentHasher {
+    hasher: ContentHasherKind,
+}
+
+enum ContentHasherKind {
+    Digest(Box<dyn Digest + Send>),
+    Hgw128(HighwayBuilder),
+    Hgw256(HighwayBuilder),
+}
+
+impl ContentHasher {
+    /// None for `HashFun::Other`, such hashes are stored as supplied.
+    pub fn new(hash_fun: &HashFun) -> Option<ContentHasher> {
+        let hasher = match hash_fun {
+            HashFun::Other => return None,
+            HashFun::Md5 => ContentHasherKind::Digest(Box::new(Md5::new())),
+            HashFun::Sha128 => ContentHasherKind::Digest(Box::new(Sha1::new())),
+            HashFun::Sha256 => ContentHasherKind::Digest(Box::new(Sha256::new())),
+            HashFun::Hgw128 => ContentHasherKind::Hgw128(HighwayBuilder::new(&CONTENT_KEY)),
+            HashFun::Hgw256 => ContentHasherKind::Hgw256(HighwayBuilder::new(&CONTENT_KEY)),
+        };
+        Some(ContentHasher { hasher: hasher })
+    }
+
+    #[inline]
+    pub fn append(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            ContentHasherKind::Digest(digest) => digest.input(data),
+            ContentHasherKind::Hgw128(hasher) => hasher.append(data),
+            ContentHasherKind::Hgw256(hasher) => hasher.append(data),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        match self.hasher {
+            ContentHasherKind::Digest(mut digest) => digest.result_str(),
+            ContentHasherKind::Hgw128(hasher) => hasher
+                .finalize128()
+                .iter()
+                .map(|w| format!("{:016x}", w))
+                .collect(),
+            ContentHasherKind::Hgw256(hasher) => hasher
+                .finalize256()
+                .iter()
+                .map(|w| format!("{:016x}", w))
+                .collect(),
+        }
+    }
+
+    /// Compares the digest with the expected hash, an empty one is filled in.
+    pub fn verify(self, hash: &mut String) -> Result<(), std::io::Error> {
+        let digest = self.finish();
+        if hash.is_empty() {
+            *hash = digest;
+            return Ok(());
+        }
+        match hash.eq_ignore_ascii_case(&digest) {
+            true => Ok(()),
+            false => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("hash mismatch: expected {}, got {}", hash, digest),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct VolumeMeta {
+    pub id: String,
+    pub path: String,
+    pub last_check_ts: u64,
+}
+
+impl VolumeMeta {
+    pub fn new() -> VolumeMeta {
+        VolumeMeta {
+            id: "".to_string(),
+            path: "".to_string(),
+            last_check_ts: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    #[inline]
+    pub fn encode(self) -> Result<Vec<u8>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        Ok(buf)
+    }
+
+    #[inline]
+    pub fn decode(payload: Vec<u8>) -> Result<VolumeMeta, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
+    }
+
+    pub fn upsert(self) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("volumes").unwrap();
+                match db.put_cf(cf, &self.id.as_str().to_owned(), self.encode().unwrap()) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    pub fn get(volume_id: String) -> Result<Option<VolumeMeta>, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("volumes").unwrap();
+                match db.get_cf(cf, volume_id.as_str()) {
+                    Ok(None) => Ok(None),
+                    Ok(r) => match VolumeMeta::decode(r.unwrap()) {
+                        Ok(res) => Ok(Some(res)),
+                        Err(e) => {
+                            error!("decode volume meta: {}", e);
+                            Err(e)
+                        }
+                    },
+                    _ => Ok(None),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn exists(volume_id: String) -> Result<bool, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("volumes").unwrap();
+                match db.get_cf(cf, volume_id.as_str()) {
+                    Ok(None) => Ok(false),
+                    Ok(_) => Ok(true),
+                    _ => Ok(false),
+                }
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct BucketMeta {
+    pub cnt_blocks: u64,
+    pub active_slots: u64,
+    pub init_size_bytes: u64,
+    pub avail_size_bytes: u64,
+    pub gc_size_bytes: u64,
+    pub ts: u64,
+}
+
+impl BucketMeta {
+    pub fn new() -> BucketMeta {
+        BucketMeta {
+            cnt_blocks: 0,
+            active_slots: 0,
+            init_size_bytes: 0,
+            avail_size_bytes: 0,
+            gc_size_bytes: 0,
+            ts: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    #[inline]
+    pub fn db_id(id: u32, volume_id: &String) -> String {
+        format!("{:05}-{}", id, &volume_id.to_owned())
+    }
+
+    pub fn upsert(self, id: u32, volume_id: &String) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("buckets").unwrap();
+                match db.put_cf(
+                    cf,
+                    BucketMeta::db_id(id, &volume_id.to_owned()),
+                    self.encode().unwrap(),
+                ) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    // bucket meta for a batch, fetched once and collected in `buckets`
+    fn load_in<'a>(
+        db: &DB,
+        buckets: &'a mut HashMap<String, BucketMeta>,
+        bucket_db_id: &String,
+    ) -> Result<&'a mut BucketMeta, ()> {
+        if !buckets.contains_key(bucket_db_id) {
+            let buckets_cf = db.cf_handle("buckets").unwrap();
+            let bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
+                Ok(None) => return Err(()),
+                Ok(r) => match BucketMeta::decode(r.unwrap()) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        error!("decode bucket meta: {}", e);
+                        return Err(());
+                    }
+                },
+                _ => return Err(()),
+            };
+            buckets.insert(bucket_db_id.to_owned(), bucket);
+        }
+        Ok(buckets.get_mut(bucket_db_id).unwrap())
+    }
+
+    // puts the updated buckets into the batch
+    fn put_all_in(db: &DB, batch: &mut WriteBatch, buckets: HashMap<String, BucketMeta>) {
+        let buckets_cf = db.cf_handle("buckets").unwrap();
+        for (bucket_db_id, bucket) in buckets {
+            let _ = batch.put_cf(buckets_cf, bucket_db_id, bucket.encode().unwrap());
+        }
+    }
+
+    #[inline]
+    pub fn encode(self) -> Result<Vec<u8>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        Ok(buf)
+    }
+
+    #[inline]
+    pub fn decode(payload: Vec<u8>) -> Result<BucketMeta, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
+    }
+
+    pub fn get(bucket_db_id: String) -> Result<Option<BucketMeta>, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("buckets").unwrap();
+                match db.get_cf(cf, bucket_db_id.as_str()) {
+                    Ok(None) => Ok(None),
+                    Ok(r) => match BucketMeta::decode(r.unwrap()) {
+                        Ok(res) => Ok(Some(res)),
+                        Err(e) => {
+                            error!("decode bucket meta: {}", e);
+                            Err(e)
+                        }
+                    },
+                    _ => Ok(None),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Points the moved blocks to their rewritten records, retires the source segments,
+    /// clears the intents of the output segments and returns the reclaimed bytes
+    /// to the bucket in one batch.
+    /// Blocks changed since they were copied are left as is, their copies become garbage.
+    /// Fails without changes if a write to one of the source segments is still pending.
+    pub fn compact(
+        volume_id: &String,
+        bucket_id: u32,
+        moves: &Vec<(BlockMeta, u32, u64)>,
+        outputs: &Vec<u32>,
+        retired: &Vec<u32>,
+        reclaimed_bytes: u64,
+    ) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let blocks_cf = db.cf_handle("blocks").unwrap();
+                let buckets_cf = db.cf_handle("buckets").unwrap();
+                let move_queue_cf = db.cf_handle("move_queue").unwrap();
+
+                let bucket_db_id = BucketMeta::db_id(bucket_id, volume_id);
+                let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
+                    Ok(Some(r)) => match BucketMeta::decode(r) {
+                        Ok(res) => res,
+                        Err(e) => {
+                            error!("decode bucket meta: {}", e);
+                            return Err(());
+                        }
+                    },
+                    _ => return Err(()),
+                };
+                // reclaimed bytes also cover aborted records which were never counted as garbage
+                bucket.gc_size_bytes = bucket.gc_size_bytes.saturating_sub(reclaimed_bytes);
+                bucket.avail_size_bytes += reclaimed_bytes;
+
+                let intents_cf = db.cf_handle("intents").unwrap();
+                let iterator = db.iterator_cf(intents_cf, IteratorMode::Start).unwrap();
+                for (_k, v) in iterator {
+                    match Intent::decode(v.to_vec()) {
+                        Ok(i) => {
+                            if i.is_write_to(volume_id, bucket_id)
+                                && retired.contains(&i.segment_id)
+                            {
+                                warn!(
+                                    "compaction of bucket {}: pending write to segment {}",
+                                    bucket_db_id, i.segment_id
+                                );
+                                return Err(());
+                            }
+                        }
+                        Err(e) => {
+                            error!("decode intent: {}", e);
+                            return Err(());
+                        }
+                    }
+                }
+
+                let trash_cf = db.cf_handle("trash").unwrap();
+                let mut batch = WriteBatch::default();
+                for (prev, segment_id, offset) in moves.iter() {
+                    // a moved record belongs either to the block or to its trashed version
+                    for cf in [blocks_cf, trash_cf].iter() {
+                        if let Ok(Some(r)) = db.get_cf(*cf, prev.id.as_str()) {
+                            let mut current = match BlockMeta::decode(r) {
+                                Ok(res) => res,
+                                Err(e) => {
+                                    error!("decode block meta: {}", e);
+                                    return Err(());
+                                }
+                            };
+                            if current.volume_id.eq(&prev.volume_id)
+                                && current.bucket_id == prev.bucket_id
+                                && current.segment_id == prev.segment_id
+                                && current.offset == prev.offset
+                            {
+                                current.segment_id = *segment_id;
+                                current.offset = *offset;
+                                let _ = batch.put_cf(
+                                    *cf,
+                                    &current.id.as_str().to_owned(),
+                                    current.clone().encode().unwrap(),
+                                );
+                            }
+                        }
+                    }
+                }
+                for segment_id in retired.iter() {
+                    let r = RetiredSegment::new(volume_id, bucket_id, *segment_id);
+                    let _ = batch.put_cf(move_queue_cf, r.db_id(), r.encode().unwrap());
+                }
+                for segment_id in outputs.iter() {
+                    let intent = Intent::compact(volume_id, bucket_id, *segment_id);
+                    let _ = batch.delete_cf(intents_cf, intent.db_id());
+                }
+                let _ = batch.put_cf(
+                    buckets_cf,
+                    bucket_db_id.to_owned(),
+                    bucket.encode().unwrap(),
+                );
+
+                match db.write(batch) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+
+    pub fn exists(bucket_id: String) -> Result<bool, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("buckets").unwrap();
+                match db.get_cf(cf, bucket_id.as_str()) {
+                    Ok(None) => Ok(false),
+                    Ok(_) => Ok(true),
+                    _ => Ok(false),
+                }
+            }
+            None => Ok(false),
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
-pub struct VolumeMeta {
-    pub id: String,
-    pub path: String,
-    pub last_check_ts: u64,
+/// Segment rewritten by the compactor and waiting to be unlinked.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetiredSegment {
+    pub volume_id: String,
+    pub bucket_id: u32,
+    pub segment_id: u32,
+    pub ts: u64,
 }
 
-impl VolumeMeta {
-    pub fn new() -> VolumeMeta {
-        VolumeMeta {
-            id: "".to_string(),
-            path: "".to_string(),
-            last_check_ts: SystemTime::now()
+impl RetiredSegment {
+    pub fn new(volume_id: &String, bucket_id: u32, segment_id: u32) -> RetiredSegment {
+        RetiredSegment {
+            volume_id: volume_id.to_owned(),
+            bucket_id: bucket_id,
+            segment_id: segment_id,
+            ts: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
         }
     }
 
+    #[inline]
+    pub fn db_id(&self) -> String {
+        format!(
+            "{}:{:08}",
+            BucketMeta::db_id(self.bucket_id, &self.volume_id),
+            self.segment_id
+        )
+    }
+
     #[inline]
     pub fn encode(self) -> Result<Vec<u8>, Error> {
         let mut buf: Vec<u8> = Vec::new();
@@ -474,16 +1781,36 @@ impl VolumeMeta {
     }
 
     #[inline]
-    pub fn decode(payload: Vec<u8>) -> Result<VolumeMeta, Error> {
-        let r: VolumeMeta = rmps::from_read_ref(&payload).unwrap();
-        Ok(r)
+    pub fn decode(payload: Vec<u8>) -> Result<RetiredSegment, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
     }
 
-    pub fn upsert(self) -> Result<(), ()> {
+    pub fn fetch_all() -> Result<Vec<RetiredSegment>, Error> {
+        let mut res: Vec<RetiredSegment> = vec![];
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let move_queue_cf = db.cf_handle("move_queue").unwrap();
+                let iterator = db.iterator_cf(move_queue_cf, IteratorMode::Start).unwrap();
+                for (_k, v) in iterator {
+                    match RetiredSegment::decode(v.to_vec()) {
+                        Ok(r) => res.push(r),
+                        Err(e) => {
+                            error!("decode retired segment: {}", e);
+                            return Err(e);
+                        }
+                    }
+                }
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+
+    pub fn delete(self) -> Result<(), ()> {
         match METADB.write().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("volumes").unwrap();
-                match db.put_cf(cf, &self.id.as_str().to_owned(), self.encode().unwrap()) {
+                let cf = db.cf_handle("move_queue").unwrap();
+                match db.delete_cf(cf, self.db_id()) {
                     Ok(_) => Ok(()),
                     Err(_) => Err(()),
                 }
@@ -491,61 +1818,222 @@ impl VolumeMeta {
             None => Err(()),
         }
     }
+}
 
-    pub fn get(volume_id: String) -> Result<Option<VolumeMeta>, Error> {
-        match METADB.read().unwrap().as_ref() {
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum IntentKind {
+    Write,
+    Compact,
+}
+
+/// Journal entry for a segment write which isn't committed to the blocks yet.
+/// It's stored before the payload hits the disk and cleared by the commit batch,
+/// unfinished intents are rolled back on startup.
+/// Deletes need no intent: the block is moved to the delete queue in one batch
+/// and the queue is replayed by gc, purging a record twice is harmless.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Intent {
+    pub kind: IntentKind,
+    pub block_id: String,
+    pub volume_id: String,
+    pub bucket_id: u32,
+    pub segment_id: u32,
+    pub offset: u64,
+    pub size: u64,
+    pub ts: u64,
+}
+
+impl Intent {
+    /// Intent for the record the block meta points to
+    pub fn write(meta: &BlockMeta) -> Intent {
+        Intent {
+            kind: IntentKind::Write,
+            block_id: meta.id.to_owned(),
+            volume_id: meta.volume_id.to_owned(),
+            bucket_id: meta.bucket_id,
+            segment_id: meta.segment_id,
+            offset: meta.offset,
+            size: meta.size,
+            ts: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Intent for a segment built by the compactor
+    pub fn compact(volume_id: &String, bucket_id: u32, segment_id: u32) -> Intent {
+        Intent {
+            kind: IntentKind::Compact,
+            block_id: "".to_string(),
+            volume_id: volume_id.to_owned(),
+            bucket_id: bucket_id,
+            segment_id: segment_id,
+            offset: 0,
+            size: 0,
+            ts: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Whether the intent is an uncommitted write into the bucket
+    pub fn is_write_to(&self, volume_id: &String, bucket_id: u32) -> bool {
+        self.kind == IntentKind::Write
+            && self.volume_id.eq(volume_id)
+            && self.bucket_id == bucket_id
+    }
+
+    #[inline]
+    pub fn db_id(&self) -> String {
+        let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
+        match self.kind {
+            IntentKind::Write => format!(
+                "{}:{:08}:{:016x}",
+                bucket_db_id, self.segment_id, self.offset
+            ),
+            IntentKind::Compact => format!("{}:{:08}", bucket_db_id, self.segment_id),
+        }
+    }
+
+    #[inline]
+    pub fn encode(self) -> Result<Vec<u8>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        Ok(buf)
+    }
+
+    #[inline]
+    pub fn decode(payload: Vec<u8>) -> Result<Intent, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
+    }
+
+    pub fn store(&self, sync: bool) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => self.store_in(db, sync),
+            None => Err(()),
+        }
+    }
+
+    fn store_in(&self, db: &DB, sync: bool) -> Result<(), ()> {
+        let cf = db.cf_handle("intents").unwrap();
+        let mut opts = WriteOptions::default();
+        opts.set_sync(sync);
+        match db.put_cf_opt(cf, self.db_id(), self.clone().encode().unwrap(), &opts) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+
+    pub fn delete(&self) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("volumes").unwrap();
-                match db.get_cf(cf, volume_id.as_str()) {
-                    Ok(None) => Ok(None),
-                    Ok(r) => match VolumeMeta::decode(r.unwrap()) {
-                        Ok(res) => Ok(Some(res)),
-                        Err(e) => {
-                            error!("decode volume meta: {}", e);
-                            Err(e)
-                        }
-                    },
-                    _ => Ok(None),
+                let cf = db.cf_handle("intents").unwrap();
+                match db.delete_cf(cf, self.db_id()) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
                 }
             }
-            None => Ok(None),
+            None => Err(()),
         }
     }
 
-    pub fn exists(volume_id: String) -> Result<bool, Error> {
+    /// Clears the intent of an aborted record and accounts the record as garbage,
+    /// so the compactor can reclaim it.
+    pub fn rollback(&self) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => self.rollback_in(db),
+            None => Err(()),
+        }
+    }
+
+    fn rollback_in(&self, db: &DB) -> Result<(), ()> {
+        let buckets_cf = db.cf_handle("buckets").unwrap();
+        let intents_cf = db.cf_handle("intents").unwrap();
+
+        let bucket_db_id = BucketMeta::db_id(self.bucket_id, &self.volume_id);
+        let mut bucket = match db.get_cf(buckets_cf, bucket_db_id.as_str()) {
+            Ok(Some(r)) => match BucketMeta::decode(r) {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("decode bucket meta: {}", e);
+                    return Err(());
+                }
+            },
+            _ => return Err(()),
+        };
+        bucket.avail_size_bytes = bucket.avail_size_bytes.saturating_sub(self.size);
+        bucket.gc_size_bytes += self.size;
+
+        let mut batch = WriteBatch::default();
+        let _ = batch.delete_cf(intents_cf, self.db_id());
+        let _ = batch.put_cf(buckets_cf, bucket_db_id, bucket.encode().unwrap());
+        match db.write(batch) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+
+    pub fn fetch_all() -> Result<Vec<Intent>, Error> {
+        let mut res: Vec<Intent> = vec![];
         match METADB.read().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("volumes").unwrap();
-                match db.get_cf(cf, volume_id.as_str()) {
-                    Ok(None) => Ok(false),
-                    Ok(_) => Ok(true),
-                    _ => Ok(false),
+                let cf = db.cf_handle("intents").unwrap();
+                let iterator = db.iterator_cf(cf, IteratorMode::Start).unwrap();
+                for (_k, v) in iterator {
+                    match Intent::decode(v.to_vec()) {
+                        Ok(r) => res.push(r),
+                        Err(e) => {
+                            error!("decode intent: {}", e);
+                            return Err(e);
+                        }
+                    }
                 }
+                Ok(res)
             }
-            None => Ok(false),
+            None => Ok(res),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
-pub struct BucketMeta {
-    pub cnt_blocks: u64,
-    pub active_slots: u64,
-    pub init_size_bytes: u64,
-    pub avail_size_bytes: u64,
-    pub gc_size_bytes: u64,
-    pub ts: u64,
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum CorruptReason {
+    CrcMismatch,
+    HashMismatch,
+    Unreadable,
 }
 
-impl BucketMeta {
-    pub fn new() -> BucketMeta {
-        BucketMeta {
-            cnt_blocks: 0,
-            active_slots: 0,
-            init_size_bytes: 0,
-            avail_size_bytes: 0,
-            gc_size_bytes: 0,
-            ts: SystemTime::now()
+impl CorruptReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CorruptReason::CrcMismatch => "crc_mismatch",
+            CorruptReason::HashMismatch => "hash_mismatch",
+            CorruptReason::Unreadable => "unreadable",
+        }
+    }
+}
+
+/// Block found broken by the validator, kept in the `corrupt` column family
+/// until acknowledged. `location` is the gc key of the checked record, so a
+/// rewritten block isn't quarantined anymore.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CorruptBlock {
+    pub block_id: String,
+    pub reason: CorruptReason,
+    pub details: String,
+    pub location: String,
+    pub detected: u64,
+}
+
+impl CorruptBlock {
+    pub fn new(meta: &BlockMeta, reason: CorruptReason, details: String) -> CorruptBlock {
+        CorruptBlock {
+            block_id: meta.id.to_owned(),
+            reason: reason,
+            details: details,
+            location: meta.gc_key(),
+            detected: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
@@ -553,19 +2041,22 @@ impl BucketMeta {
     }
 
     #[inline]
-    pub fn db_id(id: u32, volume_id: &String) -> String {
-        format!("{:05}-{}", id, &volume_id.to_owned())
+    pub fn encode(self) -> Result<Vec<u8>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        Ok(buf)
     }
 
-    pub fn upsert(self, id: u32, volume_id: &String) -> Result<(), ()> {
+    #[inline]
+    pub fn decode(payload: Vec<u8>) -> Result<CorruptBlock, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
+    }
+
+    pub fn store(self) -> Result<(), ()> {
         match METADB.write().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("buckets").unwrap();
-                match db.put_cf(
-                    cf,
-                    BucketMeta::db_id(id, &volume_id.to_owned()),
-                    self.encode().unwrap(),
-                ) {
+                let cf = db.cf_handle("corrupt").unwrap();
+                match db.put_cf(cf, self.block_id.to_owned(), self.encode().unwrap()) {
                     Ok(_) => Ok(()),
                     Err(_) => Err(()),
                 }
@@ -574,6 +2065,93 @@ impl BucketMeta {
         }
     }
 
+    pub fn get(block_id: String) -> Result<Option<CorruptBlock>, Error> {
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("corrupt").unwrap();
+                match db.get_cf(cf, block_id.as_str()) {
+                    Ok(Some(r)) => CorruptBlock::decode(r).map(Some),
+                    _ => Ok(None),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Quarantine entry of the current record of the block, if any.
+    pub fn quarantined(meta: &BlockMeta) -> Option<CorruptBlock> {
+        match CorruptBlock::get(meta.id.to_owned()) {
+            Ok(Some(entry)) if entry.location == meta.gc_key() => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// Page of entries after the cursor (a block id, empty from the start).
+    /// Returns the cursor of the next page, None when there are no more entries.
+    pub fn list(
+        cursor: &String,
+        limit: usize,
+    ) -> Result<(Vec<CorruptBlock>, Option<String>), Error> {
+        let mut res: Vec<CorruptBlock> = vec![];
+        match METADB.read().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("corrupt").unwrap();
+                let mode = match cursor.is_empty() {
+                    true => IteratorMode::Start,
+                    false => IteratorMode::From(cursor.as_bytes(), Direction::Forward),
+                };
+                for (k, v) in db.iterator_cf(cf, mode).unwrap() {
+                    if k.as_ref() == cursor.as_bytes() {
+                        continue;
+                    }
+                    if res.len() >= limit {
+                        let last = res.last().map(|e| e.block_id.to_owned());
+                        return Ok((res, last));
+                    }
+                    match CorruptBlock::decode(v.to_vec()) {
+                        Ok(entry) => res.push(entry),
+                        Err(e) => {
+                            error!("decode corrupt block: {}", e);
+                            return Err(e);
+                        }
+                    }
+                }
+                Ok((res, None))
+            }
+            None => Ok((res, None)),
+        }
+    }
+
+    /// Removes the entry, returns false if there was none.
+    pub fn acknowledge(block_id: &String) -> Result<bool, ()> {
+        match METADB.write().unwrap().as_ref() {
+            Some(db) => {
+                let cf = db.cf_handle("corrupt").unwrap();
+                match db.get_cf(cf, block_id.as_str()) {
+                    Ok(Some(_)) => match db.delete_cf(cf, block_id.as_str()) {
+                        Ok(_) => Ok(true),
+                        Err(_) => Err(()),
+                    },
+                    Ok(None) => Ok(false),
+                    Err(_) => Err(()),
+                }
+            }
+            None => Err(()),
+        }
+    }
+}
+
+/// Deleted block which GC failed to purge. It waits in `gc_retry`, indexed by the
+/// next attempt in `gc_retry_index`, and goes to `gc_dead_letter` after the retry limit.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GcRetry {
+    pub meta: BlockMeta,
+    pub attempts: u32,
+    pub next_try: u64,
+    pub error: String,
+}
+
+impl GcRetry {
     #[inline]
     pub fn encode(self) -> Result<Vec<u8>, Error> {
         let mut buf: Vec<u8> = Vec::new();
@@ -582,42 +2160,95 @@ impl BucketMeta {
     }
 
     #[inline]
-    pub fn decode(payload: Vec<u8>) -> Result<BucketMeta, Error> {
-        let r: BucketMeta = rmps::from_read_ref(&payload).unwrap();
-        Ok(r)
+    pub fn decode(payload: Vec<u8>) -> Result<GcRetry, Error> {
+        rmps::from_read_ref(&payload).map_err(|_| Error)
     }
 
-    pub fn get(bucket_db_id: String) -> Result<Option<BucketMeta>, Error> {
+    // `gc_retry_index` orders the entries by the next attempt
+    fn index_key(&self) -> String {
+        format!("{:016x}:{}", self.next_try, self.meta.gc_key())
+    }
+
+    /// Entries with the next attempt due at `now`.
+    pub fn fetch_due(now: u64, limit: u32) -> Result<Vec<GcRetry>, Error> {
+        let mut res: Vec<GcRetry> = vec![];
         match METADB.read().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("buckets").unwrap();
-                match db.get_cf(cf, bucket_db_id.as_str()) {
-                    Ok(None) => Ok(None),
-                    Ok(r) => match BucketMeta::decode(r.unwrap()) {
-                        Ok(res) => Ok(Some(res)),
-                        Err(e) => {
-                            error!("decode bucket meta: {}", e);
-                            Err(e)
-                        }
-                    },
-                    _ => Ok(None),
+                let cf = db.cf_handle("gc_retry").unwrap();
+                let index_cf = db.cf_handle("gc_retry_index").unwrap();
+                let bound = format!("{:016x}:", now + 1);
+                let due = db
+                    .iterator_cf(index_cf, IteratorMode::Start)
+                    .unwrap()
+                    .take_while(|(k, _)| k.as_ref() < bound.as_bytes())
+                    .take(limit as usize);
+                for (_k, v) in due {
+                    match db.get_cf(cf, v.as_ref()) {
+                        Ok(Some(r)) => match GcRetry::decode(r) {
+                            Ok(r) => res.push(r),
+                            Err(e) => {
+                                error!("decode gc retry: {}", e);
+                                return Err(e);
+                            }
+                        },
+                        Ok(None) => (),
+                        Err(_) => return Err(Error),
+                    }
                 }
+                Ok(res)
             }
-            None => Ok(None),
+            None => Ok(res),
         }
     }
 
-    pub fn exists(bucket_id: String) -> Result<bool, Error> {
-        match METADB.read().unwrap().as_ref() {
+    // drops the retry entry of the record with its index key
+    fn delete_in(db: &DB, batch: &mut WriteBatch, key: &String) -> Result<(), ()> {
+        let cf = db.cf_handle("gc_retry").unwrap();
+        let index_cf = db.cf_handle("gc_retry_index").unwrap();
+        match db.get_cf(cf, key.as_str()) {
+            Ok(Some(r)) => match GcRetry::decode(r) {
+                Ok(r) => {
+                    let _ = batch.delete_cf(index_cf, r.index_key());
+                    let _ = batch.delete_cf(cf, key.as_str());
+                    Ok(())
+                }
+                Err(_) => Err(()),
+            },
+            Ok(None) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+
+    /// Takes the record out of `delete_queue` until the next attempt.
+    pub fn store(self) -> Result<(), ()> {
+        self.move_to("gc_retry")
+    }
+
+    /// Parks the record in `gc_dead_letter`, GC doesn't touch it anymore.
+    pub fn dead_letter(self) -> Result<(), ()> {
+        self.move_to("gc_dead_letter")
+    }
+
+    fn move_to(self, cf_name: &str) -> Result<(), ()> {
+        match METADB.write().unwrap().as_ref() {
             Some(db) => {
-                let cf = db.cf_handle("buckets").unwrap();
-                match db.get_cf(cf, bucket_id.as_str()) {
-                    Ok(None) => Ok(false),
-                    Ok(_) => Ok(true),
-                    _ => Ok(false),
+                let delete_queue_cf = db.cf_handle("delete_queue").unwrap();
+                let cf = db.cf_handle(cf_name).unwrap();
+                let key = self.meta.gc_key();
+                let mut batch = WriteBatch::default();
+                let _ = batch.delete_cf(delete_queue_cf, key.as_str());
+                GcRetry::delete_in(db, &mut batch, &key)?;
+                if cf_name == "gc_retry" {
+                    let index_cf = db.cf_handle("gc_retry_index").unwrap();
+                    let _ = batch.put_cf(index_cf, self.index_key(), key.as_str());
+                }
+                let _ = batch.put_cf(cf, key.as_str(), self.encode().unwrap());
+                match db.write(batch) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(()),
                 }
             }
-            None => Ok(false),
+            None => Err(()),
         }
     }
 }
@@ -1,9 +1,13 @@
 extern crate systemstat;
 
 pub mod bucket;
+pub mod compactor;
 pub mod disk;
+pub mod expiry;
 pub mod gc;
 pub mod meta;
+pub mod segment;
 pub mod status;
+pub mod throttle;
 pub mod validator;
 pub mod volume;
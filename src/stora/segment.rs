@@ -0,0 +1,575 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Durability;
+
+// record layout: magic(4) | flags(1) | id_len(2) | payload_len(8) | block id | payload
+pub const RECORD_MAGIC: &[u8; 4] = b"VBLK";
+pub const RECORD_DELETED: u8 = 0x01;
+/// the bytes of an erased record were returned to the bucket counters on erase
+pub const RECORD_RECLAIMED: u8 = 0x02;
+const RECORD_FIXED_HEADER_SIZE: u64 = 15;
+const SEGMENT_EXT: &str = "seg";
+
+pub fn segment_path(bucket_path: &String, segment_id: u32) -> String {
+    format!("{}/{:08}.{}", bucket_path, segment_id, SEGMENT_EXT)
+}
+
+pub fn sync_dir(path: &String) -> Result<(), String> {
+    match File::open(path).and_then(|dir| dir.sync_all()) {
+        Ok(_) => Ok(()),
+        Err(why) => Err(why.to_string()),
+    }
+}
+
+pub fn list_segments(bucket_path: &String) -> Vec<u32> {
+    let mut segments: Vec<u32> = vec![];
+    if let Ok(entries) = fs::read_dir(bucket_path) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mut parts = name.splitn(2, '.');
+            let (stem, ext) = (parts.next(), parts.next());
+            if ext != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Some(Ok(id)) = stem.map(|s| s.parse::<u32>()) {
+                segments.push(id);
+            }
+        }
+    }
+    segments.sort();
+    segments
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordHeader {
+    pub flags: u8,
+    pub block_id: String,
+    pub size: u64,
+}
+
+impl RecordHeader {
+    pub fn new(block_id: &String, size: u64) -> RecordHeader {
+        RecordHeader {
+            flags: 0,
+            block_id: block_id.to_owned(),
+            size: size,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        RECORD_FIXED_HEADER_SIZE + self.block_id.len() as u64
+    }
+
+    #[inline]
+    pub fn is_deleted(&self) -> bool {
+        self.flags & RECORD_DELETED != 0
+    }
+
+    #[inline]
+    pub fn is_reclaimed(&self) -> bool {
+        self.flags & RECORD_RECLAIMED != 0
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(self.len() as usize);
+        buf.extend_from_slice(RECORD_MAGIC);
+        buf.push(self.flags);
+        buf.extend_from_slice(&(self.block_id.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(self.block_id.as_bytes());
+        buf
+    }
+
+    pub fn read_at(file: &File, offset: u64) -> Result<RecordHeader, String> {
+        let mut fixed = [0u8; RECORD_FIXED_HEADER_SIZE as usize];
+        if let Err(why) = file.read_exact_at(&mut fixed, offset) {
+            return Err(why.to_string());
+        }
+        if &fixed[0..4] != RECORD_MAGIC {
+            return Err(format!("bad record magic at {}", offset));
+        }
+        let mut id_len = [0u8; 2];
+        id_len.copy_from_slice(&fixed[5..7]);
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&fixed[7..15]);
+
+        let mut block_id = vec![0u8; u16::from_le_bytes(id_len) as usize];
+        if let Err(why) = file.read_exact_at(&mut block_id, offset + RECORD_FIXED_HEADER_SIZE) {
+            return Err(why.to_string());
+        }
+        match String::from_utf8(block_id) {
+            Ok(block_id) => Ok(RecordHeader {
+                flags: fixed[4],
+                block_id: block_id,
+                size: u64::from_le_bytes(size),
+            }),
+            Err(_) => Err(format!("bad record id at {}", offset)),
+        }
+    }
+}
+
+/// Append-only writer for the active segment of a bucket.
+/// Space for a record is reserved under the writer lock, the payload itself
+/// is written with positioned writes after the lock is released.
+#[derive(Debug)]
+pub struct SegmentWriter {
+    pub bucket_path: String,
+    pub segment_id: u32,
+    pub size: u64,
+    size_limit: u64,
+    durability: Durability,
+    file: Option<Arc<File>>,
+    sealed: Vec<(u32, Instant)>,
+}
+
+impl SegmentWriter {
+    pub fn new(bucket_path: &String, size_limit: u64, durability: Durability) -> SegmentWriter {
+        SegmentWriter {
+            bucket_path: bucket_path.to_owned(),
+            segment_id: 0,
+            size: 0,
+            size_limit: size_limit,
+            durability: durability,
+            file: None,
+            sealed: vec![],
+        }
+    }
+
+    pub fn bootstrap(&mut self) -> Result<(), String> {
+        // never append to a segment of the previous run: its tail may be torn
+        self.segment_id = match list_segments(&self.bucket_path).last() {
+            Some(id) => id + 1,
+            None => 1,
+        };
+        self.size = 0;
+        self.file = None;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn size_limit(&self) -> u64 {
+        self.size_limit
+    }
+
+    #[inline]
+    pub fn durability(&self) -> &Durability {
+        &self.durability
+    }
+
+    /// Segment is cold when it isn't written anymore
+    /// and the writes reserved before sealing had time to be committed.
+    pub fn is_cold(&mut self, segment_id: u32, grace: Duration) -> bool {
+        self.sealed.retain(|(_, ts)| ts.elapsed() < grace);
+        segment_id < self.segment_id && !self.sealed.iter().any(|(id, _)| *id == segment_id)
+    }
+
+    fn seal(&mut self) {
+        self.sealed.push((self.segment_id, Instant::now()));
+        self.segment_id += 1;
+        self.size = 0;
+        self.file = None;
+    }
+
+    /// Seals the active segment and hands out a fresh segment id
+    /// for files written outside of the append path.
+    pub fn allocate(&mut self) -> u32 {
+        if self.file.is_some() {
+            self.seal();
+        }
+        let segment_id = self.segment_id;
+        self.seal();
+        segment_id
+    }
+
+    pub fn reserve(&mut self, block_id: &String, size: u64) -> Result<RecordWriter, String> {
+        if block_id.len() > u16::max_value() as usize {
+            return Err("block id is too long".to_string());
+        }
+        let header = RecordHeader::new(block_id, size);
+        let record_size = header.len() + size;
+        if self.file.is_some() && self.size > 0 && self.size + record_size > self.size_limit {
+            self.seal();
+        }
+        if self.file.is_none() {
+            let path = segment_path(&self.bucket_path, self.segment_id);
+            match OpenOptions::new()
+                .create(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+            {
+                Ok(file) => self.file = Some(Arc::new(file)),
+                Err(why) => return Err(why.to_string()),
+            }
+            if self.durability == Durability::Fsync {
+                sync_dir(&self.bucket_path)?;
+            }
+        }
+        let offset = self.size;
+        self.size += record_size;
+        Ok(RecordWriter {
+            segment_id: self.segment_id,
+            offset: offset,
+            header: header,
+            durability: self.durability.clone(),
+            file: self.file.clone().unwrap(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordWriter {
+    pub segment_id: u32,
+    pub offset: u64,
+    header: RecordHeader,
+    durability: Durability,
+    file: Arc<File>,
+}
+
+impl RecordWriter {
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Writes the record and flushes it according to the durability mode.
+    /// A failed record is marked as deleted, so the segment stays readable.
+    pub fn write(&self, payload: &[u8]) -> Result<(), String> {
+        if payload.len() as u64 != self.header.size {
+            self.abort();
+            return Err("payload size doesn't match reserved size".to_string());
+        }
+        let mut buf = self.header.encode();
+        buf.extend_from_slice(payload);
+        if let Err(why) = self.file.write_all_at(buf.as_slice(), self.offset) {
+            self.abort();
+            return Err(why.to_string());
+        }
+        if let Err(why) = self.sync() {
+            self.abort();
+            return Err(why);
+        }
+        Ok(())
+    }
+
+    /// Writes a part of the payload at the given payload position.
+    /// The record becomes valid on `complete`.
+    pub fn write_part(&self, pos: u64, part: &[u8]) -> Result<(), String> {
+        if pos + part.len() as u64 > self.header.size {
+            self.abort();
+            return Err("payload exceeds reserved size".to_string());
+        }
+        match self
+            .file
+            .write_all_at(part, self.offset + self.header.len() + pos)
+        {
+            Ok(_) => Ok(()),
+            Err(why) => {
+                self.abort();
+                Err(why.to_string())
+            }
+        }
+    }
+
+    /// Writes the header of a record written in parts and flushes the record.
+    pub fn complete(&self, written: u64) -> Result<(), String> {
+        if written != self.header.size {
+            self.abort();
+            return Err("payload size doesn't match reserved size".to_string());
+        }
+        if let Err(why) = self
+            .file
+            .write_all_at(self.header.encode().as_slice(), self.offset)
+        {
+            self.abort();
+            return Err(why.to_string());
+        }
+        if let Err(why) = self.sync() {
+            self.abort();
+            return Err(why);
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<(), String> {
+        let res = match self.durability {
+            Durability::None => Ok(()),
+            Durability::Fdatasync => self.file.sync_data(),
+            Durability::Fsync => self.file.sync_all(),
+        };
+        match res {
+            Ok(_) => Ok(()),
+            Err(why) => Err(why.to_string()),
+        }
+    }
+
+    pub fn abort(&self) {
+        if let Err(e) = abort_at(&self.file, self.offset, &self.header) {
+            error!(
+                "can't abort record at {}:{}: {}",
+                self.segment_id, self.offset, e
+            );
+        }
+    }
+}
+
+// the aborted record keeps its full length, so the segment can be scanned past it
+fn abort_at(file: &File, offset: u64, header: &RecordHeader) -> Result<(), String> {
+    let mut header = header.clone();
+    header.flags |= RECORD_DELETED;
+    if let Err(why) = file.write_all_at(header.encode().as_slice(), offset) {
+        return Err(why.to_string());
+    }
+    if header.size > 0 {
+        if let Err(why) = file.write_all_at(&[0u8], offset + header.len() + header.size - 1) {
+            return Err(why.to_string());
+        }
+    }
+    match file.sync_data() {
+        Ok(_) => Ok(()),
+        Err(why) => Err(why.to_string()),
+    }
+}
+
+/// Writes a whole segment under a temporary name, it becomes visible on `finish`.
+#[derive(Debug)]
+pub struct SegmentBuilder {
+    pub segment_id: u32,
+    pub size: u64,
+    path: String,
+    tmp_path: String,
+    file: File,
+}
+
+impl SegmentBuilder {
+    pub fn create(bucket_path: &String, segment_id: u32) -> Result<SegmentBuilder, String> {
+        let path = segment_path(bucket_path, segment_id);
+        let tmp_path = format!("{}.tmp", path);
+        match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&tmp_path)
+        {
+            Ok(file) => Ok(SegmentBuilder {
+                segment_id: segment_id,
+                size: 0,
+                path: path,
+                tmp_path: tmp_path,
+                file: file,
+            }),
+            Err(why) => Err(why.to_string()),
+        }
+    }
+
+    pub fn append(&mut self, block_id: &String, payload: &[u8]) -> Result<u64, String> {
+        let mut buf = RecordHeader::new(block_id, payload.len() as u64).encode();
+        buf.extend_from_slice(payload);
+        let offset = self.size;
+        match self.file.write_all_at(buf.as_slice(), offset) {
+            Ok(_) => {
+                self.size += buf.len() as u64;
+                Ok(offset)
+            }
+            Err(why) => Err(why.to_string()),
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        if let Err(why) = self.file.sync_all() {
+            return Err(why.to_string());
+        }
+        if let Err(why) = fs::rename(&self.tmp_path, &self.path) {
+            return Err(why.to_string());
+        }
+        match Path::new(&self.path).parent() {
+            Some(dir) => sync_dir(&dir.to_string_lossy().to_string()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn abort(self) {
+        let _ = fs::remove_file(&self.tmp_path);
+    }
+}
+
+/// Reads all record headers of a segment.
+/// Fails if the segment can't be parsed up to its end.
+pub fn scan_segment(
+    bucket_path: &String,
+    segment_id: u32,
+) -> Result<Vec<(u64, RecordHeader)>, String> {
+    let file = match File::open(segment_path(bucket_path, segment_id)) {
+        Ok(file) => file,
+        Err(why) => return Err(why.to_string()),
+    };
+    let len = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(why) => return Err(why.to_string()),
+    };
+    let mut records: Vec<(u64, RecordHeader)> = vec![];
+    let mut offset: u64 = 0;
+    while offset < len {
+        let header = RecordHeader::read_at(&file, offset)?;
+        let next = offset + header.len() + header.size;
+        if next > len {
+            return Err(format!("truncated record at {}:{}", segment_id, offset));
+        }
+        records.push((offset, header));
+        offset = next;
+    }
+    Ok(records)
+}
+
+/// Payload of a record, read in parts.
+#[derive(Debug)]
+pub struct RecordReader {
+    pub size: u64,
+    file: File,
+    payload_offset: u64,
+}
+
+impl RecordReader {
+    pub fn open(
+        bucket_path: &String,
+        segment_id: u32,
+        offset: u64,
+        block_id: &String,
+        size: u64,
+    ) -> Result<RecordReader, String> {
+        let file = match File::open(segment_path(bucket_path, segment_id)) {
+            Ok(file) => file,
+            Err(why) => return Err(why.to_string()),
+        };
+        let header = RecordHeader::read_at(&file, offset)?;
+        if !header.block_id.eq(block_id) || header.size != size {
+            return Err(format!("record mismatch at {}:{}", segment_id, offset));
+        }
+        if header.is_deleted() {
+            return Err(format!("record is deleted at {}:{}", segment_id, offset));
+        }
+        Ok(RecordReader {
+            size: size,
+            file: file,
+            payload_offset: offset + header.len(),
+        })
+    }
+
+    /// Reads up to `len` bytes of the payload from the given position
+    pub fn read(&self, pos: u64, len: u64) -> Result<Vec<u8>, String> {
+        if pos >= self.size {
+            return Ok(vec![]);
+        }
+        let mut part = vec![0u8; std::cmp::min(len, self.size - pos) as usize];
+        match self
+            .file
+            .read_exact_at(&mut part, self.payload_offset + pos)
+        {
+            Ok(_) => Ok(part),
+            Err(why) => Err(why.to_string()),
+        }
+    }
+}
+
+pub fn read_record(
+    bucket_path: &String,
+    segment_id: u32,
+    offset: u64,
+    block_id: &String,
+    size: u64,
+) -> Result<Vec<u8>, String> {
+    let reader = RecordReader::open(bucket_path, segment_id, offset, block_id, size)?;
+    reader.read(0, size)
+}
+
+pub fn mark_deleted(
+    bucket_path: &String,
+    segment_id: u32,
+    offset: u64,
+    block_id: &String,
+) -> Result<(), String> {
+    let file = match OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open(segment_path(bucket_path, segment_id))
+    {
+        Ok(file) => file,
+        Err(why) => return Err(why.to_string()),
+    };
+    let header = RecordHeader::read_at(&file, offset)?;
+    if !header.block_id.eq(block_id) {
+        return Err(format!("record mismatch at {}:{}", segment_id, offset));
+    }
+    match file.write_all_at(&[header.flags | RECORD_DELETED], offset + 4) {
+        Ok(_) => Ok(()),
+        Err(why) => Err(why.to_string()),
+    }
+}
+
+/// Overwrites the payload of the record with zeros and marks it as deleted.
+/// With `reclaimed` its bytes are already free in the bucket counters,
+/// the compactor doesn't count them again.
+pub fn erase_record(
+    bucket_path: &String,
+    segment_id: u32,
+    offset: u64,
+    block_id: &String,
+    reclaimed: bool,
+) -> Result<(), String> {
+    let file = match OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open(segment_path(bucket_path, segment_id))
+    {
+        Ok(file) => file,
+        Err(why) => return Err(why.to_string()),
+    };
+    let header = RecordHeader::read_at(&file, offset)?;
+    if !header.block_id.eq(block_id) {
+        return Err(format!("record mismatch at {}:{}", segment_id, offset));
+    }
+    let zeros = [0u8; 64 * 1024];
+    let payload_offset = offset + header.len();
+    let mut pos: u64 = 0;
+    while pos < header.size {
+        let len = std::cmp::min(header.size - pos, zeros.len() as u64) as usize;
+        if let Err(why) = file.write_all_at(&zeros[..len], payload_offset + pos) {
+            return Err(why.to_string());
+        }
+        pos += len as u64;
+    }
+    let mut flags = header.flags | RECORD_DELETED;
+    if reclaimed {
+        flags |= RECORD_RECLAIMED;
+    }
+    if let Err(why) = file.write_all_at(&[flags], offset + 4) {
+        return Err(why.to_string());
+    }
+    match file.sync_data() {
+        Ok(_) => Ok(()),
+        Err(why) => Err(why.to_string()),
+    }
+}
+
+/// Marks a reserved record as deleted whatever was written to it.
+pub fn abort_record(
+    bucket_path: &String,
+    segment_id: u32,
+    offset: u64,
+    block_id: &String,
+    size: u64,
+) -> Result<(), String> {
+    let file = match OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open(segment_path(bucket_path, segment_id))
+    {
+        Ok(file) => file,
+        Err(why) => return Err(why.to_string()),
+    };
+    abort_at(&file, offset, &RecordHeader::new(block_id, size))
+}
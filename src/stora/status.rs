@@ -19,6 +19,7 @@ lazy_static! {
     pub static ref LA: RwLock<LaStatus> = RwLock::new(LaStatus::new());
     pub static ref UPTIME: RwLock<UptimeStatus> = RwLock::new(UptimeStatus::new());
     pub static ref NET: RwLock<NetStatus> = RwLock::new(NetStatus::new());
+    pub static ref VALIDATOR: RwLock<ValidatorStatus> = RwLock::new(ValidatorStatus::new());
 }
 
 pub fn set_config(config: &Config) {
@@ -46,6 +47,7 @@ pub struct Status {
     pub la: LaStatus,
     pub uptime: UptimeStatus,
     pub net: NetStatus,
+    pub validator: ValidatorStatus,
 }
 
 impl Status {
@@ -59,6 +61,7 @@ impl Status {
             la: LaStatus::get(),
             uptime: UptimeStatus::get(),
             net: NetStatus::get(),
+            validator: ValidatorStatus::get(),
         }
     }
 }
@@ -123,6 +126,7 @@ impl StorageStatus {
         let mut initial_size: u64 = 0;
         let mut available_size: u64 = 0;
         let mut gc_size: u64 = 0;
+        let mut move_size: u64 = 0;
         for v in &disk.volumes {
             for b in v.buckets.iter() {
                 cnt_blocks += b.cnt_blocks;
@@ -130,12 +134,13 @@ impl StorageStatus {
                 initial_size += b.initial_size_bytes;
                 available_size += b.avail_size_bytes;
                 gc_size += b.gc_size_bytes;
+                move_size += b.move_size_bytes;
             }
         }
         StorageStatus {
             objects: cnt_blocks,
             gc_bytes: gc_size,
-            move_bytes: 0,
+            move_bytes: move_size,
             init_bytes: initial_size,
             avail_bytes: available_size,
             active_slots: active_slots,
@@ -243,6 +248,34 @@ impl NetStatus {
     }
 }
 
+/// Progress of the current validator pass. `position` is the last scanned
+/// block id, empty before the pass starts; rate and ETA count scanned blocks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidatorStatus {
+    pub position: String,
+    pub pass_started: u64,
+    pub scanned: u64,
+    pub checked: u64,
+    pub blocks_per_sec: f32,
+    pub eta_sec: u64,
+}
+
+impl ValidatorStatus {
+    pub fn new() -> ValidatorStatus {
+        ValidatorStatus {
+            position: "".to_string(),
+            pass_started: 0,
+            scanned: 0,
+            checked: 0,
+            blocks_per_sec: 0.0,
+            eta_sec: 0,
+        }
+    }
+    pub fn get() -> ValidatorStatus {
+        VALIDATOR.read().unwrap().to_owned()
+    }
+}
+
 pub struct PhysStats {}
 
 impl PhysStats {
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::time;
+
+/// Paces background IO so that every key (a volume id) gets at most
+/// `per_sec` units per second, 0 is unlimited.
+pub struct Throttle {
+    per_sec: u64,
+    next: HashMap<String, Instant>,
+}
+
+impl Throttle {
+    pub fn new(per_sec: u64) -> Throttle {
+        Throttle {
+            per_sec: per_sec,
+            next: HashMap::new(),
+        }
+    }
+
+    /// Waits for the slot of the key, spending `amount` pushes the next slot further.
+    pub async fn acquire(&mut self, key: &str, amount: u64) {
+        if self.per_sec == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let at = match self.next.get(key) {
+            Some(at) if *at > now => *at,
+            _ => now,
+        };
+        let cost = Duration::from_secs_f64(amount as f64 / self.per_sec as f64);
+        self.next.insert(key.to_string(), at + cost);
+        if at > now {
+            time::delay_for(at - now).await;
+        }
+    }
+}
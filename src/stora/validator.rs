@@ -1,73 +1,170 @@
 use std::time::{Duration, Instant, SystemTime};
 
-use rocksdb::IteratorMode;
+use lz4_compress::decompress;
 use tokio::time;
 
-use crate::metrics::CHECK_TIME_GAUGE;
+use crate::metrics::{CHECK_TIME_GAUGE, CORRUPT_BLOCKS_COUNTER};
 use crate::stora::disk::read_block;
-use crate::stora::meta::{BlockMeta, METADB};
+use crate::stora::meta::{
+    load_checkpoint, store_checkpoint, BlockMeta, ContentHasher, CorruptBlock, CorruptReason,
+    CrcHasher,
+};
+use crate::stora::status::{StorageStatus, ValidatorStatus, VALIDATOR};
+use crate::stora::throttle::Throttle;
 
-pub fn process(check_interval_days: u32, timeout: u32) {
+const CHECKPOINT: &str = "validator";
+
+/// Scrubs the blocks in block id order, `batch` blocks per tick. The position is
+/// checkpointed after every batch, so a restarted node resumes the pass.
+pub fn process(
+    check_interval_days: u32,
+    check_content_hash: bool,
+    batch: u32,
+    bytes_per_sec: u64,
+    timeout: u32,
+) {
     tokio::spawn(async move {
         info!("start block validator");
         let mut interval = time::interval(Duration::from_secs(timeout as u64));
+        let mut throttle = Throttle::new(bytes_per_sec);
+        let mut cursor = load_checkpoint(CHECKPOINT).unwrap_or_default();
+        let mut pass_started = Instant::now();
+        {
+            let mut p = VALIDATOR.write().unwrap();
+            *p = ValidatorStatus::new();
+            p.position = cursor.to_owned();
+            p.pass_started = unix_now();
+        }
         interval.tick().await;
         loop {
-            let mut check_list: Vec<BlockMeta> = vec![];
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            match METADB.read().unwrap().as_ref() {
-                Some(db) => {
-                    let blocks_cf = db.cf_handle("blocks").unwrap();
-                    let mut iterator = db.iterator_cf(blocks_cf, IteratorMode::Start).unwrap();
-                    loop {
-                        match iterator.next() {
-                            None => break,
-                            Some((_k, v)) => match BlockMeta::decode(v.to_vec()) {
-                                Ok(bm) => {
-                                    if bm.last_check_ts + check_interval_days as u64 * 86400 < now {
-                                        check_list.push(bm);
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("decode block meta: {}", e);
-                                    continue;
-                                }
-                            },
-                        }
-                    }
+            let now = unix_now();
+            let (blocks, next) = BlockMeta::scan(&cursor, batch as usize);
+            let scanned = blocks.len() as u64;
+            let mut checked: u64 = 0;
+            for b in blocks.iter() {
+                if b.last_check_ts + check_interval_days as u64 * 86400 >= now {
+                    continue;
                 }
-                None => {
-                    error!("can't read meta db");
+                throttle.acquire(&b.volume_id, b.size).await;
+                check_block(b, check_content_hash);
+                checked += 1;
+            }
+            cursor = next.unwrap_or_default();
+            if let Err(_) = store_checkpoint(CHECKPOINT, &cursor) {
+                error!("can't store validator checkpoint")
+            }
+            {
+                let mut p = VALIDATOR.write().unwrap();
+                p.position = cursor.to_owned();
+                p.scanned += scanned;
+                p.checked += checked;
+                let elapsed = pass_started.elapsed().as_secs_f32();
+                if elapsed > 0.0 {
+                    p.blocks_per_sec = p.scanned as f32 / elapsed;
+                }
+                let left = StorageStatus::get().objects.saturating_sub(p.scanned);
+                p.eta_sec = match p.blocks_per_sec > 0.0 {
+                    true => (left as f32 / p.blocks_per_sec) as u64,
+                    false => 0,
+                };
+                if cursor.is_empty() {
+                    info!(
+                        "validator pass done: {} blocks scanned, {} checked",
+                        p.scanned, p.checked
+                    );
+                    *p = ValidatorStatus::new();
+                    p.pass_started = unix_now();
+                    pass_started = Instant::now();
                 }
             }
-            for b in check_list.iter_mut() {
-                let now = Instant::now();
-                match read_block(&b.path) {
-                    Ok(content) => {
-                        if !b.crc.eq(&BlockMeta::crc(content)) {
-                            //todo: write in error queue
-                            error!("found wrong block content: {}", b.id)
-                        }
+            interval.tick().await;
+        }
+    });
+}
+
+/// Checks the block out of turn, returns its quarantine entry if it is corrupt.
+pub fn check_now(block_id: &String, check_content_hash: bool) -> Result<Option<CorruptBlock>, ()> {
+    match BlockMeta::get(block_id.to_owned()) {
+        Ok(Some(b)) => {
+            check_block(&b, check_content_hash);
+            Ok(CorruptBlock::quarantined(&b))
+        }
+        _ => Err(()),
+    }
+}
+
+fn check_block(b: &BlockMeta, check_content_hash: bool) {
+    let now = Instant::now();
+    match read_block(b) {
+        Ok(content) => {
+            let mut crc = CrcHasher::new();
+            crc.append(content.as_slice());
+            if !b.crc.eq(&crc.finish()) {
+                error!("found wrong block content: {}", b.id);
+                quarantine(b, CorruptReason::CrcMismatch, "crc mismatch".to_string());
+            } else if check_content_hash {
+                match content_hash_matches(b, content) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        error!("block {} doesn't match the written content hash", b.id);
+                        quarantine(
+                            b,
+                            CorruptReason::HashMismatch,
+                            "content hash mismatch".to_string(),
+                        );
                     }
                     Err(e) => {
-                        //todo: write in error queue
-                        error!("can't read the block: {}", e)
+                        error!("can't check content hash of block {}: {}", b.id, e);
+                        quarantine(b, CorruptReason::Unreadable, e);
                     }
                 }
-                b.last_check_ts = SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                let b = b.clone();
-                if let Err(_) = b.store() {
-                    error!("can't update meta block")
-                }
-                CHECK_TIME_GAUGE.set(now.elapsed().as_micros() as f64);
             }
-            interval.tick().await;
         }
-    });
+        Err(e) => {
+            error!("can't read the block: {}", e);
+            quarantine(b, CorruptReason::Unreadable, e.to_string());
+        }
+    }
+    let b = b.clone();
+    if let Err(_) = b.checked(unix_now()) {
+        error!("can't update meta block")
+    }
+    CHECK_TIME_GAUGE.set(now.elapsed().as_micros() as f64);
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// the block may have been rewritten or moved since the listing, only the
+// current record is quarantined
+fn quarantine(b: &BlockMeta, reason: CorruptReason, details: String) {
+    match BlockMeta::get_stored(b.id.to_owned()) {
+        Ok(Some(current)) if current.gc_key() == b.gc_key() => {
+            CORRUPT_BLOCKS_COUNTER
+                .with_label_values(&[reason.as_str()])
+                .inc();
+            if let Err(_) = CorruptBlock::new(b, reason, details).store() {
+                error!("can't quarantine block {}", b.id)
+            }
+        }
+        _ => (),
+    }
+}
+
+// the stored bytes passed the crc check, the original payload must give the client hash
+fn content_hash_matches(b: &BlockMeta, content: Vec<u8>) -> Result<bool, String> {
+    let mut hasher = match ContentHasher::new(&b.hash_fun) {
+        Some(hasher) if !b.hash.is_empty() => hasher,
+        _ => return Ok(true),
+    };
+    let content = match b.compressed {
+        true => decompress(content.as_slice()).map_err(|e| e.to_string())?,
+        false => content,
+    };
+    hasher.append(content.as_slice());
+    Ok(hasher.finish().eq_ignore_ascii_case(&b.hash))
 }
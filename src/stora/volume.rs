@@ -11,6 +11,7 @@ use std::path::Path;
 use std::process;
 
 use super::systemstat::Filesystem;
+use crate::config::Durability;
 use crate::stora::bucket::Bucket;
 use crate::stora::meta::VolumeMeta;
 use crypto::digest::Digest;
@@ -47,6 +48,8 @@ impl Volume {
         &mut self,
         mounts: &Vec<Filesystem>,
         bucket_size_limit: u64,
+        segment_size_limit: u64,
+        durability: &Durability,
     ) -> Result<bool, &str> {
         if !fs::metadata(&self.path).is_ok() {
             fs::create_dir_all(&self.path).expect("can't create volume");
@@ -71,7 +74,14 @@ impl Volume {
                 info!("init {} buckets for volume {}", cnt, self.path);
                 for i in 1..cnt + 1 {
                     let bucket_path = format!("{}/{}", self.path, i);
-                    let mut bucket = Bucket::new(i, &self.id, &bucket_path, bucket_size_limit);
+                    let mut bucket = Bucket::new(
+                        i,
+                        &self.id,
+                        &bucket_path,
+                        bucket_size_limit,
+                        segment_size_limit,
+                        durability,
+                    );
                     match bucket.bootstrap() {
                         Ok(_) => buckets.push(bucket),
                         Err(x) => {
@@ -0,0 +1,19 @@
+import pytest
+
+import nodes
+
+
+@pytest.fixture
+def spawn_node():
+    # starts nodes of their own for tests which need other settings or a crash,
+    # or which damage segments; they are stopped and wiped after the test
+    spawned = []
+
+    def start(storage=None):
+        node = nodes.spawn(nodes.NODE.config, storage or {})
+        spawned.append(node)
+        return node
+
+    yield start
+    for node in spawned:
+        node.stop()
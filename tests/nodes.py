@@ -0,0 +1,197 @@
+# Nodes the integration tests run against and helpers to look into their segments.
+# The shared node is described by tests/node.yml (or $VSTORAGE_NODE_CONFIG), its
+# endpoints and its volume are taken from there.
+import copy
+import json
+import os
+import shutil
+import signal
+import socket
+import struct
+import subprocess
+import tempfile
+import time
+
+import pytest
+import requests
+import yaml
+
+TESTS_DIR = os.path.dirname(os.path.abspath(__file__))
+ROOT_DIR = os.path.dirname(TESTS_DIR)
+NODE_CONFIG = os.environ.get('VSTORAGE_NODE_CONFIG', os.path.join(TESTS_DIR, 'node.yml'))
+BINARY = os.path.join(ROOT_DIR, 'target', 'debug', 'block-server')
+
+# record header: magic, flags, id length, size, then the id and the payload
+RECORD_HEADER_SIZE = 15
+RECORD_DELETED = 0x01
+RECORD_RECLAIMED = 0x02
+
+# spawned nodes listen next to the shared one
+SPAWN_PORT_SHIFT = 100
+
+
+def wait_for(check, timeout=30, interval=0.1):
+    # polls until check() returns a true value, the last value is returned
+    deadline = time.time() + timeout
+    while True:
+        res = check()
+        if res or time.time() > deadline:
+            return res
+        time.sleep(interval)
+
+
+def flip_record_byte(segment, pos):
+    with open(segment, 'r+b') as f:
+        f.seek(pos)
+        byte = f.read(1)
+        f.seek(-1, 1)
+        f.write(chr(ord(byte) ^ 0xff))
+
+
+def record_flags(segment, offset):
+    with open(segment, 'rb') as f:
+        f.seek(offset + 4)
+        return ord(f.read(1))
+
+
+class Node(object):
+    def __init__(self, config):
+        self.config = config
+        rest = config['interfaces']['rest']['lan']
+        self.endpoint = "http://" + rest
+        self.grpc_endpoint = config['interfaces']['grpc']['lan']
+        host, port = rest.rsplit(':', 1)
+        self.rest_address = (host.strip('[]'), int(port))
+        # nodes run from the repo root, relative paths start there
+        self.data_dir = os.path.join(ROOT_DIR, config['storage']['volumes'][0])
+
+    @classmethod
+    def from_file(cls, path=NODE_CONFIG):
+        with open(path) as f:
+            return cls(yaml.safe_load(f))
+
+    def status(self):
+        return requests.get(self.endpoint + "/status").json()
+
+    def metric(self, name):
+        for line in requests.get(self.endpoint + "/metrics").text.splitlines():
+            if line.startswith(name + " "):
+                return float(line.split()[1])
+        return 0.0
+
+    def block_meta(self, block_id):
+        r = requests.post(self.endpoint + "/batch/get", json={'block_ids': [block_id], 'omit_payload': True})
+        return r.json()['items'][0]['meta']
+
+    def segment_path(self, meta):
+        return os.path.join(self.data_dir, str(meta['bucket_id']), "%08d.seg" % meta['segment_id'])
+
+    def segments(self):
+        for bucket in os.listdir(self.data_dir):
+            bucket_dir = os.path.join(self.data_dir, bucket)
+            if not os.path.isdir(bucket_dir):
+                continue
+            for name in os.listdir(bucket_dir):
+                if name.endswith('.seg'):
+                    yield os.path.join(bucket_dir, name)
+
+    def segments_size(self):
+        return sum(os.path.getsize(segment) for segment in self.segments())
+
+    def find_records(self, block_id):
+        # flags of every record of the block in the segments of the node
+        found = []
+        for segment in self.segments():
+            with open(segment, 'rb') as f:
+                while True:
+                    header = f.read(RECORD_HEADER_SIZE)
+                    if len(header) < RECORD_HEADER_SIZE or 'VBLK' != header[0:4]:
+                        break
+                    id_len = struct.unpack('<H', header[5:7])[0]
+                    size = struct.unpack('<Q', header[7:15])[0]
+                    if block_id == f.read(id_len):
+                        found.append(ord(header[4]))
+                    f.seek(size, 1)
+        return found
+
+    def start_partial_put(self, block_id, size, payload):
+        # the payload is cut short, the connection is left open until the caller closes it
+        before = self.segments_size()
+        sock = socket.create_connection(self.rest_address)
+        sock.sendall(
+            "PUT /block/%s HTTP/1.1\r\nHost: localhost\r\nContent-Length: %d\r\n\r\n" % (block_id, size)
+            + payload
+        )
+        assert wait_for(lambda: self.segments_size() > before)
+        return sock
+
+    def send_partial_put(self, block_id, size, payload):
+        sock = self.start_partial_put(block_id, size, payload)
+        sock.close()
+        assert wait_for(lambda: 0 == self.status()['storage']['active_slots'])
+
+
+class SpawnedNode(Node):
+    """Node of its own with the shared node settings and `storage` on top of them,
+    it listens on shifted ports and keeps its data in a temp dir."""
+
+    def __init__(self, base, storage):
+        self.work_dir = tempfile.mkdtemp()
+        config = copy.deepcopy(base)
+        for interface in config['interfaces'].values():
+            for side, address in interface.items():
+                host, port = address.rsplit(':', 1)
+                interface[side] = "%s:%d" % (host, int(port) + SPAWN_PORT_SHIFT)
+        config['node'] = {
+            'nodename': 'spawned', 'zone': 'default', 'rack': 'rack1',
+            'work-dir': os.path.join(self.work_dir, 'temp'),
+            'pid-file': os.path.join(self.work_dir, 'block_server.pid'),
+            'logger-config': 'sblock_logger.yml',
+            'opts': {'mode': 'default'},
+        }
+        config['db'] = {
+            'meta-db-path': os.path.join(self.work_dir, 'meta'),
+            'meta-db-backup-path': os.path.join(self.work_dir, 'meta_backup'),
+            'size-calculation-interval-min': 60,
+        }
+        config['storage'].update(storage)
+        config['storage']['volumes'] = [os.path.join(self.work_dir, 'data')]
+        Node.__init__(self, config)
+        self.config_path = os.path.join(self.work_dir, 'node.yml')
+        with open(self.config_path, 'w') as f:
+            # yaml reads json as is
+            json.dump(config, f)
+        self.process = None
+
+    def start(self):
+        self.process = subprocess.Popen([BINARY, '-C', self.config_path], cwd=ROOT_DIR)
+
+        def ready():
+            try:
+                return 200 == requests.get(self.endpoint).status_code
+            except requests.ConnectionError:
+                return False
+
+        assert wait_for(ready)
+
+    def kill(self):
+        # no shutdown path runs, as on a crash
+        self.process.send_signal(signal.SIGKILL)
+        self.process.wait()
+
+    def stop(self):
+        if self.process is not None and self.process.poll() is None:
+            self.process.terminate()
+            self.process.wait()
+        shutil.rmtree(self.work_dir, ignore_errors=True)
+
+
+def spawn(base, storage):
+    if not os.path.exists(BINARY):
+        pytest.skip("block-server isn't built")
+    node = SpawnedNode(base, storage)
+    node.start()
+    return node
+
+
+NODE = Node.from_file()
@@ -6,10 +6,11 @@ import os
 import grpc
 from client import block_api_pb2
 from client import block_api_pb2_grpc
+from nodes import NODE, flip_record_byte, wait_for
 
 
 class TestGrpcApi:
-    endpoint = "[::1]:33085"
+    endpoint = NODE.grpc_endpoint
     payload = '''
         Lorem ipsum dolor sit amet, consectetur adipiscing elit. Ut blandit rhoncus magna eget sollicitudin. Ut sem nisi, molestie quis neque ut, pellentesque sollicitudin velit. Quisque consequat risus erat, vitae volutpat est faucibus posuere. Nunc ultricies turpis non ipsum consectetur tincidunt sit amet sed urna. Donec fermentum ut elit ut auctor. Morbi et semper nulla, quis rhoncus massa. Curabitur consequat lorem sapien, vehicula tincidunt metus viverra nec. Nam consequat orci justo, ac malesuada diam scelerisque sed. Phasellus ut ligula purus. Ut elementum odio eget nunc euismod, eu pharetra eros efficitur. Donec faucibus massa eget mi pellentesque, bibendum sollicitudin elit efficitur. Nunc ante lacus, viverra vitae congue aliquet, molestie ut risus.
         Vivamus viverra molestie nulla, vel maximus odio vestibulum vitae. Quisque tempus blandit mi, a fringilla leo. In quis nulla quis quam fringilla tincidunt. Nam sit amet elit eget diam molestie mattis in sed ipsum. Sed tincidunt, odio at feugiat placerat, purus nisi tincidunt dolor, vitae ornare lorem nisl eget enim. Praesent neque dolor, tincidunt eget justo et, ullamcorper consectetur ligula. Ut vulputate arcu erat, in sagittis ligula cursus ac. Etiam vel cursus leo, vehicula tempus tortor. Donec ipsum risus, interdum id iaculis eget, condimentum a urna.
@@ -19,6 +20,7 @@ class TestGrpcApi:
         Aliquam scelerisque metus ante. Nulla tempus quam diam, in consequat ex semper ut. Integer viverra urna odio, quis mattis metus elementum ac. Nullam id leo non dui fermentum pulvinar vel eu massa. Nunc porta tempor turpis vel ultrices. In ac arcu eu dui gravida euismod. Aenean pellentesque maximus magna, eget facilisis nisi vestibulum eget. Praesent laoreet velit eget rhoncus ullamcorper. Nulla facilisi. Morbi aliquet quam ut egestas convallis. Ut vel lorem id neque ultrices tincidunt in.
     '''
     random_payload = "\x00" + os.urandom(4 * 1024 * 1024) + "\x00"
+    rest_endpoint = NODE.endpoint
     client = None
 
     @classmethod
@@ -34,6 +36,7 @@ class TestGrpcApi:
     def test_status(self):
         res = self.client.Status(block_api_pb2.StatusRequest())
         assert "normal" == res.node.status
+        assert res.validator.checked <= res.validator.scanned
 
     def test_insert(self):
         block_id = str(uuid.uuid4())
@@ -217,4 +220,350 @@ class TestGrpcApi:
             res = self.client.Get(get_request)
             raise Exception("found")
         except:
-            pass
\ No newline at end of file
+            pass
+
+    def test_put_get_stream(self):
+        block_id = str(uuid.uuid4())
+        chunk_size = 64 * 1024
+        chunks = [self.random_payload[i:i + chunk_size]
+                  for i in range(0, len(self.random_payload), chunk_size)]
+
+        def frames():
+            yield block_api_pb2.PutStreamRequest(
+                block_id=block_id,
+                size=len(self.random_payload),
+                options=block_api_pb2.WriteOptions(
+                    content_type='application/octet-stream',
+                    compress=False,
+                ),
+                payload=chunks[0],
+            )
+            for chunk in chunks[1:]:
+                yield block_api_pb2.PutStreamRequest(payload=chunk)
+
+        res = self.client.PutStream(frames())
+        assert res.block_id == block_id
+        assert res.meta.size == len(self.random_payload)
+
+        get_request = block_api_pb2.GetRequest(
+            block_id=block_id,
+        )
+        frames = list(self.client.GetStream(get_request))
+        assert frames[0].meta.size == len(self.random_payload)
+        assert all(not f.HasField("meta") for f in frames[1:])
+        assert "".join(f.payload for f in frames) == self.random_payload
+
+    def test_get_range(self):
+        block_id = str(uuid.uuid4())
+        insert_request = block_api_pb2.InsertRequest(
+            block_id=block_id,
+            payload=self.payload,
+            options=block_api_pb2.WriteOptions(
+                content_type='plain/text',
+                compress=True,
+            )
+        )
+        res = self.client.Insert(insert_request)
+        assert res.block_id == block_id
+
+        get_request = block_api_pb2.GetRequest(
+            block_id=block_id,
+            allow_compressed=True,
+            offset=10,
+            length=100,
+        )
+        res = self.client.Get(get_request)
+        assert res.compressed == False
+        assert res.payload == self.payload[10:110]
+
+        get_tail = block_api_pb2.GetRequest(
+            block_id=block_id,
+            offset=len(self.payload) - 5,
+        )
+        frames = list(self.client.GetStream(get_tail))
+        assert "".join(f.payload for f in frames) == self.payload[-5:]
+
+    def test_user_meta(self):
+        block_id = str(uuid.uuid4())
+        insert_request = block_api_pb2.InsertRequest(
+            block_id=block_id,
+            payload=self.payload,
+            options=block_api_pb2.WriteOptions(
+                content_type='plain/text',
+                user_meta={'owner': 'alice', 'source': 'test'},
+            )
+        )
+        res = self.client.Insert(insert_request)
+        assert res.meta.user_meta['owner'] == 'alice'
+
+        res = self.client.Get(block_api_pb2.GetRequest(block_id=block_id))
+        assert dict(res.meta.user_meta) == {'owner': 'alice', 'source': 'test'}
+
+    def test_update_meta(self):
+        block_id = str(uuid.uuid4())
+        insert_request = block_api_pb2.InsertRequest(
+            block_id=block_id,
+            payload=self.payload,
+            options=block_api_pb2.WriteOptions(
+                content_type='plain/text',
+                user_meta={'owner': 'alice', 'source': 'test'},
+            )
+        )
+        res = self.client.Insert(insert_request)
+        crc = res.meta.crc
+
+        try:
+            self.client.UpdateMeta(block_api_pb2.UpdateMetaRequest(
+                block_id=block_id,
+                crc="stale",
+                content_type='text/markdown',
+            ))
+            raise Exception("updated")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.FAILED_PRECONDITION
+
+        res = self.client.UpdateMeta(block_api_pb2.UpdateMetaRequest(
+            block_id=block_id,
+            crc=crc,
+            content_type='text/markdown',
+            user_meta={'owner': 'bob', 'source': ''},
+        ))
+        assert res.meta.content_type == 'text/markdown'
+        assert dict(res.meta.user_meta) == {'owner': 'bob'}
+
+        res = self.client.Get(block_api_pb2.GetRequest(block_id=block_id))
+        assert res.meta.crc == crc
+        assert res.payload == self.payload
+        assert dict(res.meta.user_meta) == {'owner': 'bob'}
+
+    def test_list_object_blocks(self):
+        object_id = str(uuid.uuid4())
+        block_ids = sorted([str(uuid.uuid4()) for _ in range(3)])
+        for block_id in block_ids:
+            self.client.Insert(block_api_pb2.InsertRequest(
+                block_id=block_id,
+                object_id=object_id,
+                payload=self.payload,
+            ))
+
+        res = self.client.ListObjectBlocks(block_api_pb2.ListObjectBlocksRequest(
+            object_id=object_id,
+        ))
+        assert res.object_id == object_id
+        assert [b.block_id for b in res.blocks] == block_ids
+        assert all(b.meta.size == len(self.payload) for b in res.blocks)
+
+    def test_list(self):
+        content_type = 'test/' + str(uuid.uuid4())
+        block_ids = sorted([str(uuid.uuid4()) for _ in range(3)])
+        for block_id in block_ids:
+            self.client.Insert(block_api_pb2.InsertRequest(
+                block_id=block_id,
+                payload=self.payload,
+                options=block_api_pb2.WriteOptions(content_type=content_type),
+            ))
+
+        frames = list(self.client.List(block_api_pb2.ListRequest(
+            content_type=content_type,
+        )))
+        assert [f.block_id for f in frames] == block_ids
+        assert all(f.meta.content_type == content_type for f in frames)
+
+        frames = list(self.client.List(block_api_pb2.ListRequest(
+            cursor=block_ids[0],
+            limit=1,
+            content_type=content_type,
+        )))
+        assert [f.block_id for f in frames] == block_ids[1:2]
+
+    def test_delete_object(self):
+        object_id = str(uuid.uuid4())
+        block_ids = sorted([str(uuid.uuid4()) for _ in range(3)])
+        for block_id in block_ids:
+            self.client.Insert(block_api_pb2.InsertRequest(
+                block_id=block_id,
+                object_id=object_id,
+                payload=self.payload,
+            ))
+
+        res = self.client.DeleteObject(block_api_pb2.DeleteObjectRequest(
+            object_id=object_id,
+        ))
+        assert sorted(res.block_ids) == block_ids
+
+        res = self.client.ListObjectBlocks(block_api_pb2.ListObjectBlocksRequest(
+            object_id=object_id,
+        ))
+        assert len(res.blocks) == 0
+
+    def test_batch(self):
+        block_ids = [str(uuid.uuid4()) for _ in range(3)]
+        for block_id in block_ids[:2]:
+            self.client.Insert(block_api_pb2.InsertRequest(
+                block_id=block_id,
+                payload=self.payload,
+            ))
+
+        res = self.client.BatchExists(block_api_pb2.BatchRequest(block_ids=block_ids))
+        assert list(res.found) == [True, True, False]
+
+        res = self.client.BatchGet(block_api_pb2.BatchGetRequest(block_ids=block_ids))
+        assert [i.payload for i in res.items[:2]] == [self.payload, self.payload]
+        assert res.items[2].error != ""
+
+        res = self.client.BatchGet(block_api_pb2.BatchGetRequest(
+            block_ids=block_ids,
+            omit_payload=True,
+        ))
+        assert res.items[0].payload == ""
+        assert res.items[0].meta.size == len(self.payload)
+
+        res = self.client.BatchDelete(block_api_pb2.BatchRequest(block_ids=block_ids))
+        assert sorted(res.block_ids) == sorted(block_ids[:2])
+
+        res = self.client.BatchExists(block_api_pb2.BatchRequest(block_ids=block_ids))
+        assert list(res.found) == [False, False, False]
+
+    def test_expected_crc(self):
+        block_id = str(uuid.uuid4())
+        res = self.client.Insert(block_api_pb2.InsertRequest(
+            block_id=block_id,
+            payload="text1",
+        ))
+        crc = res.meta.crc
+
+        try:
+            self.client.Upsert(block_api_pb2.UpsertRequest(
+                block_id=block_id,
+                payload="text2",
+                expected_crc="stale",
+            ))
+            raise Exception("written")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.FAILED_PRECONDITION
+
+        res = self.client.Upsert(block_api_pb2.UpsertRequest(
+            block_id=block_id,
+            payload="text2",
+            expected_crc=crc,
+        ))
+        assert res.meta.crc != crc
+
+        try:
+            self.client.Append(block_api_pb2.AppendRequest(
+                block_id=block_id,
+                payload="text3",
+                expected_crc=crc,
+            ))
+            raise Exception("appended")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.FAILED_PRECONDITION
+
+        res = self.client.Get(block_api_pb2.GetRequest(block_id=block_id))
+        assert res.payload == "text2"
+
+    def test_hash_verification(self):
+        block_id = str(uuid.uuid4())
+        try:
+            self.client.Insert(block_api_pb2.InsertRequest(
+                block_id=block_id,
+                payload=self.payload,
+                options=block_api_pb2.WriteOptions(
+                    hash=hashlib.md5("other").hexdigest(),
+                    hash_fun=block_api_pb2.MD5,
+                ),
+            ))
+            raise Exception("written")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.DATA_LOSS
+
+        res = self.client.Insert(block_api_pb2.InsertRequest(
+            block_id=block_id,
+            payload=self.payload,
+            options=block_api_pb2.WriteOptions(
+                hash_fun=block_api_pb2.SHA256,
+            ),
+        ))
+        assert res.meta.hash == hashlib.sha256(self.payload).hexdigest()
+
+    def test_corrupt(self):
+        res = self.client.ListCorrupt(block_api_pb2.ListCorruptRequest(limit=10))
+        assert len(res.entries) <= 10
+
+        try:
+            self.client.AcknowledgeCorrupt(block_api_pb2.AcknowledgeCorruptRequest(block_id=str(uuid.uuid4())))
+            raise Exception("acknowledged")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.NOT_FOUND
+
+    def test_corrupt_record(self, spawn_node):
+        # the segment is edited in place, so the record lives on a node of its own
+        node = spawn_node()
+        client = block_api_pb2_grpc.BlockApiStub(grpc.insecure_channel(node.grpc_endpoint))
+        block_id = str(uuid.uuid4())
+        client.Insert(block_api_pb2.InsertRequest(block_id=block_id, payload=self.payload))
+        meta = node.block_meta(block_id)
+
+        # flip the first payload byte of the record: fixed header, block id, payload
+        flip_record_byte(node.segment_path(meta), meta['offset'] + 15 + len(block_id))
+        assert 200 == requests.post(node.endpoint + "/corrupt/" + block_id).status_code
+
+        res = client.ListCorrupt(block_api_pb2.ListCorruptRequest(limit=10000))
+        assert block_id in [e.block_id for e in res.entries]
+
+        try:
+            res, call = client.Get.with_call(block_api_pb2.GetRequest(block_id=block_id))
+            assert 'block is quarantined' in dict(call.initial_metadata())['warning']
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.DATA_LOSS
+
+        client.AcknowledgeCorrupt(block_api_pb2.AcknowledgeCorruptRequest(block_id=block_id))
+        client.Delete(block_api_pb2.DeleteRequest(block_id=block_id))
+
+    def test_undelete(self):
+        block_id = str(uuid.uuid4())
+        self.client.Insert(block_api_pb2.InsertRequest(block_id=block_id, payload="text1"))
+        self.client.Delete(block_api_pb2.DeleteRequest(block_id=block_id))
+
+        res = self.client.Undelete(block_api_pb2.UndeleteRequest(block_id=block_id))
+        assert res.block_id == block_id
+        assert res.meta.size == 5
+        res = self.client.Get(block_api_pb2.GetRequest(block_id=block_id))
+        assert res.payload == "text1"
+
+        try:
+            self.client.Undelete(block_api_pb2.UndeleteRequest(block_id=block_id))
+            raise Exception("undeleted")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.NOT_FOUND
+
+    def test_direct_delete(self):
+        block_id = str(uuid.uuid4())
+        self.client.Insert(block_api_pb2.InsertRequest(block_id=block_id, payload="text1"))
+        res = self.client.Delete(block_api_pb2.DeleteRequest(
+            block_id=block_id,
+            options=block_api_pb2.DeleteRequest.Options(direct=True),
+        ))
+        assert res.block_id == block_id
+        try:
+            self.client.Undelete(block_api_pb2.UndeleteRequest(block_id=block_id))
+            raise Exception("undeleted")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.NOT_FOUND
+
+    def test_ttl(self):
+        block_id = str(uuid.uuid4())
+        res = self.client.Insert(block_api_pb2.InsertRequest(
+            block_id=block_id,
+            payload="text1",
+            options=block_api_pb2.WriteOptions(ttl=1),
+        ))
+        assert res.meta.expires_at > 0
+
+        assert wait_for(lambda: not self.client.Exists(block_api_pb2.ExistsRequest(block_id=block_id)).found)
+        try:
+            self.client.Get(block_api_pb2.GetRequest(block_id=block_id))
+            raise Exception("found")
+        except grpc.RpcError as e:
+            assert e.code() == grpc.StatusCode.NOT_FOUND
+
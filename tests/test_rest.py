@@ -1,12 +1,18 @@
+import base64
 import pytest
 import requests
 import uuid
 import hashlib
 import os
+import struct
+import time
+
+from nodes import NODE, flip_record_byte, record_flags, wait_for
 
 
 class TestHttpApi:
-    endpoint = "http://[::1]:33087"
+    node = NODE
+    endpoint = NODE.endpoint
     payload = '''
         Lorem ipsum dolor sit amet, consectetur adipiscing elit. Ut blandit rhoncus magna eget sollicitudin. Ut sem nisi, molestie quis neque ut, pellentesque sollicitudin velit. Quisque consequat risus erat, vitae volutpat est faucibus posuere. Nunc ultricies turpis non ipsum consectetur tincidunt sit amet sed urna. Donec fermentum ut elit ut auctor. Morbi et semper nulla, quis rhoncus massa. Curabitur consequat lorem sapien, vehicula tincidunt metus viverra nec. Nam consequat orci justo, ac malesuada diam scelerisque sed. Phasellus ut ligula purus. Ut elementum odio eget nunc euismod, eu pharetra eros efficitur. Donec faucibus massa eget mi pellentesque, bibendum sollicitudin elit efficitur. Nunc ante lacus, viverra vitae congue aliquet, molestie ut risus.
         Vivamus viverra molestie nulla, vel maximus odio vestibulum vitae. Quisque tempus blandit mi, a fringilla leo. In quis nulla quis quam fringilla tincidunt. Nam sit amet elit eget diam molestie mattis in sed ipsum. Sed tincidunt, odio at feugiat placerat, purus nisi tincidunt dolor, vitae ornare lorem nisl eget enim. Praesent neque dolor, tincidunt eget justo et, ullamcorper consectetur ligula. Ut vulputate arcu erat, in sagittis ligula cursus ac. Etiam vel cursus leo, vehicula tempus tortor. Donec ipsum risus, interdum id iaculis eget, condimentum a urna.
@@ -28,6 +34,8 @@ class TestHttpApi:
         assert 200 == r.status_code
         r = r.json()
         assert "normal" == r["node"]["status"]
+        assert "position" in r["validator"]
+        assert r["validator"]["checked"] <= r["validator"]["scanned"]
 
     def test_metrics(self):
         url = self.endpoint + "/metrics"
@@ -43,7 +51,8 @@ class TestHttpApi:
             data=self.payload,
             headers={
                 'content-type': 'text/plain',
-                'v-hash-fun': '0',
+                'v-object-id': 'object1',
+                'v-hash-fun': '1',
                 'v-hash': hashlib.md5(self.payload).hexdigest(),
                 'v-compress': 'lz4',
             }
@@ -51,12 +60,22 @@ class TestHttpApi:
         assert 204 == r.status_code
 
         r = requests.head(url)
-        assert 302 == r.status_code
+        assert 200 == r.status_code
+        assert 'text/plain' == r.headers['content-type']
+        assert 'object1' == r.headers['v-object-id']
+        assert '1' == r.headers['v-hash-fun']
+        assert hashlib.md5(self.payload).hexdigest() == r.headers['v-hash']
+        assert str(len(self.payload)) == r.headers['v-size']
 
         r = requests.get(url)
         assert 200 == r.status_code
+        assert 'text/plain' == r.headers['content-type']
+        assert 'object1' == r.headers['v-object-id']
         assert self.payload == r.text
 
+        r = requests.head(self.endpoint + "/block/" + str(uuid.uuid4()))
+        assert 404 == r.status_code
+
     def test_etag(self):
         object_id = str(uuid.uuid4())
         url = self.endpoint + "/block/" + object_id
@@ -188,6 +207,23 @@ class TestHttpApi:
         )
         assert 204 == r.status_code
 
+    def test_chunked_put(self):
+        chunks = lambda: iter([self.payload[i:i + 1000] for i in range(0, len(self.payload), 1000)])
+        for headers in [{}, {'v-compress': 'lz4'}]:
+            object_url = self.endpoint + "/block/" + str(uuid.uuid4())
+            r = requests.put(object_url, data=chunks(), headers=headers)
+            assert 204 == r.status_code
+
+            r = requests.get(object_url)
+            assert 200 == r.status_code
+            assert self.payload == r.content
+
+            r = requests.post(self.endpoint + "/block_append/" + object_url.split("/")[-1], data=chunks())
+            assert 204 == r.status_code
+
+            r = requests.get(object_url)
+            assert self.payload + self.payload == r.content
+
     def test_append(self):
         oid = "append_id"
         object_url = self.endpoint + "/block/" + oid
@@ -208,3 +244,589 @@ class TestHttpApi:
         r = requests.get(object_url)
         assert 200 == r.status_code
         assert r.text == "text1text2"
+
+    def test_stream_4M(self):
+        object_url = self.endpoint + "/block/" + str(uuid.uuid4())
+
+        r = requests.put(
+            object_url,
+            data=self.random_payload,
+        )
+        assert 204 == r.status_code
+
+        r = requests.get(object_url, stream=True)
+        assert 200 == r.status_code
+        assert str(len(self.random_payload)) == r.headers["content-length"]
+        assert self.random_payload == r.raw.read()
+
+        r = requests.post(
+            self.endpoint + "/block_append/" + object_url.split("/")[-1],
+            data=self.random_payload,
+        )
+        assert 204 == r.status_code
+
+        r = requests.get(object_url)
+        assert 200 == r.status_code
+        assert self.random_payload + self.random_payload == r.content
+
+    def test_range(self):
+        object_url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(
+            object_url,
+            data=self.payload,
+            headers={
+                'v-compress': 'lz4',
+            }
+        )
+        assert 204 == r.status_code
+
+        r = requests.get(object_url, headers={'range': 'bytes=10-109'})
+        assert 206 == r.status_code
+        assert "bytes" == r.headers["accept-ranges"]
+        assert "bytes 10-109/%d" % len(self.payload) == r.headers["content-range"]
+        assert self.payload[10:110] == r.text
+
+        r = requests.get(object_url, headers={'range': 'bytes=-5'})
+        assert 206 == r.status_code
+        assert self.payload[-5:] == r.text
+
+        r = requests.get(object_url, headers={'range': 'bytes=%d-' % len(self.payload)})
+        assert 416 == r.status_code
+        assert "bytes */%d" % len(self.payload) == r.headers["content-range"]
+
+        etag = requests.get(object_url).headers["etag"]
+        r = requests.get(object_url, headers={'range': 'bytes=0-9', 'if-range': etag})
+        assert 206 == r.status_code
+        assert self.payload[:10] == r.text
+
+        r = requests.get(object_url, headers={'range': 'bytes=0-9', 'if-range': '"stale"'})
+        assert 200 == r.status_code
+        assert self.payload == r.text
+
+    def test_user_meta(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(
+            url,
+            data=self.payload,
+            headers={
+                'x-v-meta-owner': 'alice',
+                'x-v-meta-filename': 'lorem.txt',
+            }
+        )
+        assert 204 == r.status_code
+
+        r = requests.head(url)
+        assert 200 == r.status_code
+        assert 'alice' == r.headers['x-v-meta-owner']
+        assert 'lorem.txt' == r.headers['x-v-meta-filename']
+
+        r = requests.get(url)
+        assert 'alice' == r.headers['x-v-meta-owner']
+
+    def test_patch_meta(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(
+            url,
+            data=self.payload,
+            headers={
+                'content-type': 'text/plain',
+                'x-v-meta-owner': 'alice',
+            }
+        )
+        assert 204 == r.status_code
+        etag = requests.head(url).headers['etag']
+
+        r = requests.patch(url, headers={'if-match': '"stale"', 'v-object-id': 'object2'})
+        assert 412 == r.status_code
+
+        r = requests.patch(
+            url,
+            headers={
+                'if-match': etag,
+                'content-type': 'text/markdown',
+                'v-object-id': 'object2',
+                'x-v-meta-owner': 'bob',
+            }
+        )
+        assert 204 == r.status_code
+
+        r = requests.get(url)
+        assert 200 == r.status_code
+        assert etag == r.headers['etag']
+        assert 'text/markdown' == r.headers['content-type']
+        assert 'object2' == r.headers['v-object-id']
+        assert 'bob' == r.headers['x-v-meta-owner']
+        assert self.payload == r.text
+
+        r = requests.patch(self.endpoint + "/block/" + str(uuid.uuid4()))
+        assert 404 == r.status_code
+
+    def test_object_blocks(self):
+        object_id = str(uuid.uuid4())
+        block_ids = sorted([str(uuid.uuid4()) for _ in range(3)])
+        for block_id in block_ids:
+            r = requests.put(
+                self.endpoint + "/block/" + block_id,
+                data=self.payload,
+                headers={'v-object-id': object_id},
+            )
+            assert 204 == r.status_code
+
+        r = requests.get(self.endpoint + "/object/" + object_id + "/blocks")
+        assert 200 == r.status_code
+        assert block_ids == [b['id'] for b in r.json()]
+
+        r = requests.delete(self.endpoint + "/block/" + block_ids[0])
+        assert 204 == r.status_code
+
+        r = requests.get(self.endpoint + "/object/" + object_id + "/blocks")
+        assert block_ids[1:] == [b['id'] for b in r.json()]
+
+    def test_list_blocks(self):
+        content_type = 'test/' + str(uuid.uuid4())
+        block_ids = sorted([str(uuid.uuid4()) for _ in range(3)])
+        for block_id in block_ids:
+            r = requests.put(
+                self.endpoint + "/block/" + block_id,
+                data=self.payload,
+                headers={'content-type': content_type},
+            )
+            assert 204 == r.status_code
+
+        found = []
+        cursor = ""
+        while True:
+            r = requests.get(
+                self.endpoint + "/blocks",
+                params={'cursor': cursor, 'limit': 2, 'content_type': content_type},
+            )
+            assert 200 == r.status_code
+            page = r.json()
+            assert len(page['blocks']) <= 2
+            found += [b['id'] for b in page['blocks']]
+            if page['cursor'] is None:
+                break
+            cursor = page['cursor']
+        assert block_ids == found
+
+        r = requests.get(
+            self.endpoint + "/blocks",
+            params={'content_type': content_type, 'size_min': len(self.payload) + 1},
+        )
+        assert [] == [b['id'] for b in r.json()['blocks']]
+
+    def test_delete_object(self):
+        object_id = str(uuid.uuid4())
+        block_ids = sorted([str(uuid.uuid4()) for _ in range(3)])
+        for block_id in block_ids:
+            r = requests.put(
+                self.endpoint + "/block/" + block_id,
+                data=self.payload,
+                headers={'v-object-id': object_id},
+            )
+            assert 204 == r.status_code
+
+        r = requests.delete(self.endpoint + "/object/" + object_id)
+        assert 200 == r.status_code
+        assert block_ids == sorted(r.json()['block_ids'])
+
+        for block_id in block_ids:
+            r = requests.get(self.endpoint + "/block/" + block_id)
+            assert 404 == r.status_code
+
+        r = requests.delete(self.endpoint + "/object/" + object_id)
+        assert 404 == r.status_code
+
+    def test_object_mixed_case(self):
+        object_id = "Object-" + str(uuid.uuid4()).upper() + " A+B"
+        object_path = self.endpoint + "/object/" + object_id.replace(" ", "%20")
+        block_id = str(uuid.uuid4())
+        r = requests.put(
+            self.endpoint + "/block/" + block_id,
+            data=self.payload,
+            headers={'v-object-id': object_id},
+        )
+        assert 204 == r.status_code
+
+        r = requests.get(self.endpoint + "/object/" + object_id.lower().replace(" ", "%20") + "/blocks")
+        assert [] == r.json()
+
+        r = requests.get(object_path + "/blocks")
+        assert 200 == r.status_code
+        assert [block_id] == [b['id'] for b in r.json()]
+
+        r = requests.delete(object_path)
+        assert 200 == r.status_code
+        assert [block_id] == r.json()['block_ids']
+        assert object_id == r.json()['object_id']
+
+    def test_batch(self):
+        block_ids = [str(uuid.uuid4()) for _ in range(3)]
+        for block_id in block_ids[:2]:
+            r = requests.put(self.endpoint + "/block/" + block_id, data=self.payload)
+            assert 204 == r.status_code
+
+        r = requests.post(self.endpoint + "/batch/exists", json={'block_ids': block_ids})
+        assert 200 == r.status_code
+        assert [True, True, False] == [i['found'] for i in r.json()['items']]
+
+        r = requests.post(self.endpoint + "/batch/get", json={'block_ids': block_ids})
+        assert 200 == r.status_code
+        items = r.json()['items']
+        assert len(self.payload) == items[0]['meta']['orig_size']
+        assert self.payload == base64.b64decode(items[0]['payload'])
+        assert 'error' in items[2]
+
+        r = requests.post(self.endpoint + "/batch/get", json={'block_ids': block_ids, 'omit_payload': True})
+        assert 200 == r.status_code
+        assert [False, False] == ['payload' in i for i in r.json()['items'][:2]]
+
+        r = requests.post(self.endpoint + "/batch/delete", json={'block_ids': block_ids})
+        assert 200 == r.status_code
+        assert sorted(block_ids[:2]) == sorted(r.json()['block_ids'])
+
+        r = requests.post(self.endpoint + "/batch/exists", json={'block_ids': block_ids})
+        assert [False, False, False] == [i['found'] for i in r.json()['items']]
+
+    def test_conditional_write(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(url, data="text1", headers={'if-none-match': '*'})
+        assert 204 == r.status_code
+
+        r = requests.post(url, data="text2", headers={'if-none-match': '*'})
+        assert 412 == r.status_code
+
+        etag = requests.head(url).headers['etag']
+        r = requests.post(url, data="text2", headers={'if-match': '"stale"'})
+        assert 412 == r.status_code
+
+        r = requests.post(url, data="text2", headers={'if-match': etag})
+        assert 204 == r.status_code
+
+        r = requests.post(
+            self.endpoint + "/block_append/" + url.split("/")[-1],
+            data="text3",
+            headers={'if-match': etag},
+        )
+        assert 412 == r.status_code
+
+        r = requests.get(url)
+        assert "text2" == r.text
+
+    def test_upsert_stats(self):
+        status_url = self.endpoint + "/status"
+        object_url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(object_url, data=self.payload)
+        assert 204 == r.status_code
+
+        status_after_put = requests.get(status_url).json()['storage']
+
+        r = requests.post(object_url, data=self.payload)
+        assert 204 == r.status_code
+
+        status_after_upsert = requests.get(status_url).json()['storage']
+        assert status_after_put['objects'] == status_after_upsert['objects']
+        assert status_after_put['gc_bytes'] + len(self.payload) == status_after_upsert['gc_bytes']
+        assert status_after_put['avail_bytes'] - len(self.payload) == status_after_upsert['avail_bytes']
+
+        r = requests.get(object_url)
+        assert self.payload == r.text
+
+    def test_hash_verification(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(
+            url,
+            data=self.payload,
+            headers={'v-hash-fun': '1', 'v-hash': hashlib.md5("other").hexdigest()},
+        )
+        assert 422 == r.status_code
+        assert 404 == requests.head(url).status_code
+        # the rejected record is flagged deleted
+        assert [0x01] == [flags & 0x01 for flags in self.node.find_records(url.split("/")[-1])]
+
+        r = requests.put(
+            url,
+            data=self.payload,
+            headers={'v-hash-fun': '2', 'v-hash': hashlib.sha1(self.payload).hexdigest()},
+        )
+        assert 204 == r.status_code
+
+        # without v-hash-fun a client hash is stored as sent, a missing one is computed
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        assert 204 == requests.put(url, data=self.payload, headers={'v-hash': 'client-hash'}).status_code
+        r = requests.head(url)
+        assert '0' == r.headers['v-hash-fun']
+        assert 'client-hash' == r.headers['v-hash']
+
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        assert 204 == requests.put(url, data=self.payload).status_code
+        r = requests.head(url)
+        assert '4' == r.headers['v-hash-fun']
+        assert 32 == len(r.headers['v-hash'])
+
+        # a compressed payload is rejected before it is written
+        block_id = str(uuid.uuid4())
+        r = requests.put(
+            self.endpoint + "/block/" + block_id,
+            data=self.payload,
+            headers={'v-hash-fun': '1', 'v-hash': hashlib.md5("other").hexdigest(), 'v-compress': 'lz4'},
+        )
+        assert 422 == r.status_code
+        assert [] == self.node.find_records(block_id)
+
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        r = requests.put(url, data=self.payload, headers={'v-hash-fun': '3', 'v-compress': 'lz4'})
+        assert 204 == r.status_code
+        assert hashlib.sha256(self.payload).hexdigest() == requests.head(url).headers['v-hash']
+
+        r = requests.post(
+            self.endpoint + "/block_append/" + url.split("/")[-1],
+            data="tail",
+            headers={'v-hash': hashlib.sha256("other").hexdigest()},
+        )
+        assert 422 == r.status_code
+
+        r = requests.post(self.endpoint + "/block_append/" + url.split("/")[-1], data="tail")
+        assert 204 == r.status_code
+        assert hashlib.sha256(self.payload + "tail").hexdigest() == requests.head(url).headers['v-hash']
+
+    def test_corrupt(self):
+        url = self.endpoint + "/corrupt"
+        r = requests.get(url, params={'limit': '10'})
+        assert 200 == r.status_code
+        assert len(r.json()["blocks"]) <= 10
+
+        assert 404 == requests.delete(url + "/" + str(uuid.uuid4())).status_code
+
+        block_url = self.endpoint + "/block/" + str(uuid.uuid4())
+        assert 204 == requests.put(block_url, data=self.payload).status_code
+        r = requests.get(block_url)
+        assert 200 == r.status_code
+        assert 'warning' not in r.headers
+        assert 204 == requests.post(self.endpoint + "/corrupt/" + block_url.split("/")[-1]).status_code
+        assert 404 == requests.post(url + "/" + str(uuid.uuid4())).status_code
+
+    def test_corrupt_record(self, spawn_node):
+        # the segment is edited in place, so the record lives on a node of its own
+        node = spawn_node()
+        object_id = str(uuid.uuid4())
+        block_id = str(uuid.uuid4())
+        block_url = node.endpoint + "/block/" + block_id
+        r = requests.put(block_url, data=self.payload, headers={'v-object-id': object_id})
+        assert 204 == r.status_code
+        meta = requests.get(node.endpoint + "/object/" + object_id + "/blocks").json()[0]
+
+        # flip the first payload byte of the record: fixed header, block id, payload
+        segment = node.segment_path(meta)
+        flip_record_byte(segment, meta['offset'] + 15 + len(block_id))
+
+        r = requests.post(node.endpoint + "/corrupt/" + block_id)
+        assert 200 == r.status_code
+        assert block_id == r.json()['block_id']
+        assert 'CrcMismatch' == r.json()['reason']
+
+        entries = requests.get(node.endpoint + "/corrupt", params={'limit': '10000'}).json()["blocks"]
+        assert block_id in [e['block_id'] for e in entries]
+
+        r = requests.get(block_url)
+        assert 500 == r.status_code or 'block is quarantined' in r.headers['warning']
+
+        assert 204 == requests.delete(node.endpoint + "/corrupt/" + block_id).status_code
+        assert 204 == requests.delete(block_url).status_code
+
+    def test_gc_metrics(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        assert 204 == requests.put(url, data=self.payload).status_code
+        # the replaced version is purged right away, deleted blocks wait in the trash
+        assert 204 == requests.post(url, data="text2").status_code
+        assert wait_for(lambda: -1 != requests.get(self.endpoint + "/metrics").text.find("gc_purged_blocks_total"))
+
+    def test_gc_retry(self, spawn_node):
+        node = spawn_node()
+        # the purge of a replaced record fails while its header doesn't match the block
+        records = {}
+        for name in ['dead', 'retried']:
+            block_id = str(uuid.uuid4())
+            url = node.endpoint + "/block/" + block_id
+            assert 204 == requests.put(url, data=self.payload).status_code
+            meta = node.block_meta(block_id)
+            segment = node.segment_path(meta)
+            records[name] = (segment, meta['offset'])
+            flip_record_byte(segment, meta['offset'] + 15)
+            assert 204 == requests.post(url, data="text2").status_code
+
+        failed = node.metric("gc_failed_purges_total")
+        dead = node.metric("gc_dead_lettered_blocks_total")
+        assert wait_for(lambda: node.metric("gc_failed_purges_total") >= failed + 2)
+
+        segment, offset = records['retried']
+        flip_record_byte(segment, offset + 15)
+        assert wait_for(lambda: record_flags(segment, offset) & 0x01)
+        assert wait_for(lambda: node.metric("gc_dead_lettered_blocks_total") > dead)
+
+    def test_undelete(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        assert 404 == requests.request("UNDELETE", url).status_code
+
+        assert 204 == requests.put(url, data=self.payload).status_code
+        assert 204 == requests.delete(url).status_code
+        assert 404 == requests.get(url).status_code
+
+        assert 204 == requests.request("UNDELETE", url).status_code
+        r = requests.get(url)
+        assert 200 == r.status_code
+        assert self.payload == r.text
+        assert 404 == requests.request("UNDELETE", url).status_code
+
+        assert 204 == requests.delete(url).status_code
+        assert 204 == requests.put(url, data="text2").status_code
+        assert 409 == requests.request("UNDELETE", url).status_code
+
+    def test_direct_delete(self):
+        block_id = str(uuid.uuid4())
+        url = self.endpoint + "/block/" + block_id
+        assert 404 == requests.delete(url, params={'direct': 'true'}).status_code
+
+        assert 204 == requests.put(url, data=self.payload).status_code
+        assert 204 == requests.delete(url, params={'direct': 'true'}).status_code
+        assert 404 == requests.get(url).status_code
+        assert 404 == requests.request("UNDELETE", url).status_code
+        # erased records are flagged as deleted and already reclaimed
+        assert [0x03] == [flags & 0x03 for flags in self.node.find_records(block_id)]
+
+        assert 204 == requests.put(url, data=self.payload).status_code
+        assert 204 == requests.delete(url).status_code
+        assert 204 == requests.delete(url, params={'direct': 'true'}).status_code
+        assert 404 == requests.request("UNDELETE", url).status_code
+        assert [0x03, 0x03] == [flags & 0x03 for flags in self.node.find_records(block_id)]
+
+    def test_ttl(self):
+        url = self.endpoint + "/block/" + str(uuid.uuid4())
+        assert 204 == requests.put(url, data=self.payload, headers={'v-ttl': '1'}).status_code
+        r = requests.head(url)
+        assert 200 == r.status_code
+        assert int(r.headers['v-expires-at']) > 0
+
+        assert wait_for(lambda: 404 == requests.get(url).status_code)
+        assert 404 == requests.head(url).status_code
+
+        # an expired block counts as absent for new writes
+        assert 204 == requests.put(url, data="text2").status_code
+        r = requests.get(url)
+        assert 200 == r.status_code
+        assert 'v-expires-at' not in r.headers
+
+    def test_segment_layout(self):
+        block_id = str(uuid.uuid4())
+        assert 204 == requests.put(self.endpoint + "/block/" + block_id, data=self.payload).status_code
+        meta = self.node.block_meta(block_id)
+
+        # the block is a record in a bucket segment: magic, flags, id length, size, id, payload
+        with open(self.node.segment_path(meta), 'rb') as f:
+            f.seek(meta['offset'])
+            header = f.read(15)
+            assert 'VBLK' == header[0:4]
+            assert 0 == ord(header[4])
+            assert len(block_id) == struct.unpack('<H', header[5:7])[0]
+            assert len(self.payload) == struct.unpack('<Q', header[7:15])[0]
+            assert block_id == f.read(len(block_id))
+            assert self.payload == f.read(len(self.payload))
+
+        bucket_dir = os.path.dirname(self.node.segment_path(meta))
+        assert [] == [name for name in os.listdir(bucket_dir) if '.seg' not in name]
+
+    def test_compaction(self):
+        # two records share a segment, one of them becomes garbage when its block is replaced
+        block_ids = [str(uuid.uuid4()) for _ in range(300)]
+        for block_id in block_ids:
+            r = requests.put(self.endpoint + "/block/" + block_id, data=block_id + self.payload)
+            assert 204 == r.status_code
+        kept = block_ids[0::2]
+        for block_id in block_ids[1::2]:
+            r = requests.post(self.endpoint + "/block/" + block_id, data="text2")
+            assert 204 == r.status_code
+        locations = dict((block_id, self.node.block_meta(block_id)['segment_id']) for block_id in kept)
+
+        assert wait_for(lambda: [b for b in kept if self.node.block_meta(b)['segment_id'] != locations[b]], timeout=60)
+        assert self.node.metric("compaction_segments_total") > 0
+
+        for block_id in kept:
+            r = requests.get(self.endpoint + "/block/" + block_id)
+            assert 200 == r.status_code
+            assert block_id + self.payload == r.content
+        for block_id in block_ids[1::2]:
+            assert "text2" == requests.get(self.endpoint + "/block/" + block_id).content
+
+    def test_interrupted_write(self):
+        # a payload cut short is never committed, the id stays free for the retry
+        block_id = str(uuid.uuid4())
+        url = self.endpoint + "/block/" + block_id
+        self.node.send_partial_put(block_id, len(self.payload), self.payload[:100])
+        assert 404 == requests.head(url).status_code
+        assert 404 == requests.get(url).status_code
+
+        assert 204 == requests.put(url, data=self.payload).status_code
+        r = requests.get(url)
+        assert 200 == r.status_code
+        assert self.payload == r.content
+
+    def test_write_intent_rollback(self):
+        # the record reserved for an interrupted write is rolled back as a deleted one
+        block_id = str(uuid.uuid4())
+        self.node.send_partial_put(block_id, len(self.payload), self.payload[:100])
+        assert [0x01] == [flags & 0x01 for flags in self.node.find_records(block_id)]
+
+        assert 204 == requests.put(self.endpoint + "/block/" + block_id, data=self.payload).status_code
+        assert [0x00, 0x01] == sorted([flags & 0x01 for flags in self.node.find_records(block_id)])
+
+    def test_write_intent_recovery(self, spawn_node):
+        # a write cut by a crash is rolled back at the next start, the compactor must
+        # leave the aborted record alone
+        node = spawn_node({'compaction-garbage-ratio': 2})
+        before = node.status()['storage']
+        block_id = str(uuid.uuid4())
+        node.start_partial_put(block_id, len(self.payload), self.payload[:100])
+        node.kill()
+        node.start()
+
+        assert [0x01] == [flags & 0x01 for flags in node.find_records(block_id)]
+        assert 404 == requests.head(node.endpoint + "/block/" + block_id).status_code
+        after = node.status()['storage']
+        assert before['objects'] == after['objects']
+        assert before['gc_bytes'] + len(self.payload) == after['gc_bytes']
+        assert before['avail_bytes'] - len(self.payload) == after['avail_bytes']
+
+    def test_content_hash_check(self):
+        # the stored bytes, decompressed if needed, still give the hash the client sent
+        for headers in [{}, {'v-compress': 'lz4'}]:
+            block_id = str(uuid.uuid4())
+            headers.update({'v-hash-fun': '1', 'v-hash': hashlib.md5(self.payload).hexdigest()})
+            r = requests.put(self.endpoint + "/block/" + block_id, data=self.payload, headers=headers)
+            assert 204 == r.status_code
+            assert 204 == requests.post(self.endpoint + "/corrupt/" + block_id).status_code
+            assert 'warning' not in requests.get(self.endpoint + "/block/" + block_id).headers
+
+        # hash-less blocks are checked by crc only
+        block_id = str(uuid.uuid4())
+        r = requests.put(self.endpoint + "/block/" + block_id, data=self.payload, headers={'v-hash-fun': '0'})
+        assert 204 == r.status_code
+        assert 204 == requests.post(self.endpoint + "/corrupt/" + block_id).status_code
+
+    def test_gc_window(self, spawn_node):
+        # the node is started an hour before its only gc window
+        start = time.localtime(time.time() + 3600)
+        end = time.localtime(time.time() + 7200)
+        window = "%02d:%02d-%02d:%02d" % (start.tm_hour, start.tm_min, end.tm_hour, end.tm_min)
+        node = spawn_node({'gc-windows': [window]})
+        block_id = str(uuid.uuid4())
+        url = node.endpoint + "/block/" + block_id
+        assert 204 == requests.put(url, data=self.payload).status_code
+        meta = node.block_meta(block_id)
+
+        # the replaced version is queued but not purged outside the window
+        assert 204 == requests.post(url, data="text2").status_code
+        idle = node.metric("gc_idle_ticks_total")
+        assert wait_for(lambda: node.metric("gc_idle_ticks_total") >= idle + 2)
+        assert 0 == record_flags(node.segment_path(meta), meta['offset']) & 0x01
+        assert 0 == node.metric("gc_purged_blocks_total")
+        assert "text2" == requests.get(url).content